@@ -48,6 +48,10 @@ pub fn bad_resource_id() -> Error {
 pub fn not_supported() -> Error {
   custom_error("NotSupported", "The operation is not supported")
 }
+
+pub fn permission_denied(message: impl Into<Cow<'static, str>>) -> Error {
+  custom_error("PermissionDenied", message)
+}
 /// A simple error type that lets the creator specify both the error message and
 /// the error class name. This type is private; externally it only ever appears
 /// wrapped in an `anyhow::Error`. To retrieve the error class name from a wrapped
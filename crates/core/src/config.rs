@@ -0,0 +1,271 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use wkr_runtime::{wasi::WasiParams, EnvironmentBuilder, HostBindings, Permissions};
+
+/// Duration of an epoch tick, the granularity of the timeouts
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Extensions of the manifests looked up next to a module, in order
+const MANIFEST_EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+/// The bindings registered by [`HostBindings::default`]
+const BUILTIN_BINDINGS: [&str; 2] = ["fetch", "database"];
+
+/// The configuration a function runs with, usually read from a manifest deployed next to
+/// its module: `hello.toml` or `hello.json` for `hello.wasm`.
+///
+/// ```toml
+/// argv = ["--verbose"]
+/// bindings = ["fetch"]
+/// streaming = true
+///
+/// [env]
+/// LOG_LEVEL = "debug"
+///
+/// [dirs]
+/// "/data" = "/srv/functions/hello/data"
+///
+/// [limits]
+/// memory = 67108864
+/// fuel = 100000000
+///
+/// [timeouts]
+/// init_ms = 1000
+/// call_ms = 500
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FunctionConfig {
+    /// Arguments passed to the guest after argv[0], the name of the module
+    pub argv: Vec<String>,
+    /// Environment variables exposed to the guest
+    pub env: BTreeMap<String, String>,
+    /// Host directories exposed to the guest, by guest path
+    pub dirs: BTreeMap<String, String>,
+    pub limits: FunctionLimits,
+    pub timeouts: FunctionTimeouts,
+    /// The built-in host bindings the guest can reach, all of them when unset
+    pub bindings: Option<Vec<String>>,
+    /// The host calls the guest is allowed to perform, all of them when unset
+    pub permissions: Option<Permissions>,
+    /// Hands the bodies of HTTP requests and responses to the guest as `fetch` body
+    /// resources instead of buffering them in its request and response
+    pub streaming: bool,
+}
+
+/// Resources a function can use, unlimited when unset
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FunctionLimits {
+    /// Maximum size of each linear memory, in bytes
+    pub memory: Option<usize>,
+    /// Maximum number of elements of each table
+    pub table_elements: Option<u32>,
+    /// Fuel available to the initialization code and to each call
+    pub fuel: Option<u64>,
+}
+
+/// How long a function can run, unlimited when unset
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FunctionTimeouts {
+    /// Time allowed to the initialization code (`_start`, `wapc_init`), in milliseconds
+    pub init_ms: Option<u64>,
+    /// Time allowed to each call, in milliseconds
+    pub call_ms: Option<u64>,
+}
+
+impl FunctionConfig {
+    /// Reads a TOML manifest
+    pub fn from_toml(manifest: &str) -> Result<Self> {
+        Ok(toml::from_str(manifest)?)
+    }
+
+    /// Reads a JSON manifest
+    pub fn from_json(manifest: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(manifest)?)
+    }
+
+    /// Reads a manifest in the given `format`, `toml` or `json`
+    pub fn from_manifest(manifest: &[u8], format: &str) -> Result<Self> {
+        match format {
+            "toml" => Self::from_toml(std::str::from_utf8(manifest)?),
+            "json" => Self::from_json(manifest),
+            _ => Err(anyhow!("unknown manifest format: {}", format)),
+        }
+    }
+
+    /// Reads the manifest at `path`, TOML or JSON according to its extension
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let manifest = tokio::fs::read(path).await?;
+        let format = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        Self::from_manifest(&manifest, format)
+    }
+
+    /// Reads the manifest deployed next to the module at `module_path`, or returns the
+    /// default configuration when there is none
+    pub async fn for_module(module_path: &Path) -> Result<Self> {
+        for path in manifest_paths(module_path) {
+            if tokio::fs::metadata(&path).await.is_ok() {
+                return Self::from_file(&path).await;
+            }
+        }
+        Ok(FunctionConfig::default())
+    }
+
+    /// The WASI parameters of the guest, `module_name` being its argv[0]
+    pub fn wasi_params(&self, module_name: &str) -> WasiParams {
+        WasiParams {
+            argv: std::iter::once(module_name.to_owned())
+                .chain(self.argv.iter().cloned())
+                .collect(),
+            map_dirs: self
+                .dirs
+                .iter()
+                .map(|(guest, host)| (guest.clone(), host.clone()))
+                .collect(),
+            env_vars: self
+                .env
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            preopened_dirs: Vec::new(),
+        }
+    }
+
+    /// The host bindings the guest can reach
+    pub fn host_bindings(&self) -> HostBindings {
+        let mut host_bindings = HostBindings::default();
+        if let Some(enabled) = &self.bindings {
+            for binding in BUILTIN_BINDINGS {
+                if !enabled.iter().any(|name| name == binding) {
+                    host_bindings.unregister(binding);
+                }
+            }
+        }
+        host_bindings
+    }
+
+    /// Configures `builder` to run the function, `module_name` being the argv[0] of the
+    /// guest
+    pub fn apply<'a>(
+        &self,
+        builder: EnvironmentBuilder<'a>,
+        module_name: &str,
+    ) -> EnvironmentBuilder<'a> {
+        let mut builder = builder
+            .wasi_params(self.wasi_params(module_name))
+            .host_bindings(self.host_bindings());
+        if let Some(permissions) = &self.permissions {
+            builder = builder.permissions(permissions.clone());
+        }
+        if let Some(memory) = self.limits.memory {
+            builder = builder.memory_limit(memory);
+        }
+        if let Some(elements) = self.limits.table_elements {
+            builder = builder.table_elements_limit(elements);
+        }
+        if let Some(fuel) = self.limits.fuel {
+            builder = builder.fuel_budgets(fuel, fuel);
+        }
+        if self.timeouts.init_ms.is_some() || self.timeouts.call_ms.is_some() {
+            builder = builder
+                .enable_epoch_interruptions(
+                    timeout_ticks(self.timeouts.init_ms),
+                    timeout_ticks(self.timeouts.call_ms),
+                )
+                .epoch_tick(EPOCH_TICK);
+        }
+        builder
+    }
+}
+
+/// The paths a manifest of the module at `module_path` can be deployed at
+pub fn manifest_paths(module_path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    MANIFEST_EXTENSIONS
+        .iter()
+        .map(move |extension| module_path.with_extension(extension))
+}
+
+/// Deadline of the unset timeouts, far enough to never expire without overflowing the
+/// epoch counter
+const NO_TIMEOUT: u64 = u64::MAX / 2;
+
+/// The number of epoch ticks a timeout lasts, at least one
+fn timeout_ticks(timeout_ms: Option<u64>) -> u64 {
+    match timeout_ms {
+        Some(ms) => (ms / EPOCH_TICK.as_millis() as u64).max(1),
+        None => NO_TIMEOUT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_manifests() {
+        let toml = FunctionConfig::from_toml(
+            r#"
+            argv = ["--verbose"]
+            bindings = ["fetch"]
+
+            [env]
+            LOG_LEVEL = "debug"
+
+            [dirs]
+            "/data" = "/srv/data"
+
+            [limits]
+            memory = 1048576
+
+            [timeouts]
+            call_ms = 500
+            "#,
+        )
+        .unwrap();
+        let json = FunctionConfig::from_json(
+            br#"{
+                "argv": ["--verbose"],
+                "bindings": ["fetch"],
+                "env": {"LOG_LEVEL": "debug"},
+                "dirs": {"/data": "/srv/data"},
+                "limits": {"memory": 1048576},
+                "timeouts": {"call_ms": 500}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+
+        let params = toml.wasi_params("hello.wasm");
+        assert_eq!(params.argv, vec!["hello.wasm", "--verbose"]);
+        assert_eq!(params.env_vars, vec![("LOG_LEVEL".to_owned(), "debug".to_owned())]);
+        assert_eq!(params.map_dirs, vec![("/data".to_owned(), "/srv/data".to_owned())]);
+        assert_eq!(toml.limits.memory, Some(1048576));
+        assert_eq!(toml.timeouts.init_ms, None);
+
+        assert!(FunctionConfig::from_json(br#"{"memory": 1}"#).is_err());
+    }
+
+    #[test]
+    fn timeouts_in_ticks() {
+        assert_eq!(timeout_ticks(Some(500)), 50);
+        assert_eq!(timeout_ticks(Some(1)), 1);
+        assert_eq!(timeout_ticks(None), NO_TIMEOUT);
+    }
+
+    #[test]
+    fn manifests_next_to_the_module() {
+        let paths: Vec<_> = manifest_paths(Path::new("functions/hello.wasm")).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("functions/hello.toml"),
+                PathBuf::from("functions/hello.json")
+            ]
+        );
+    }
+}
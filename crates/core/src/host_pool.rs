@@ -1,156 +1,359 @@
-// use bytes::BytesMut;
-// use crate::pool::{HostPool, HostPoolBuilder};
-// use http::Method;
-// use regress::Flags;
-// use reqwest::blocking::Client;
-// use reqwest::blocking::RequestBuilder;
-// use reqwest::blocking::Response;
-// use reqwest::Url;
-// use urlpattern::UrlPattern;
-// use urlpattern::UrlPatternInit;
-// use urlpattern::UrlPatternMatchInput;
-// use wapc_pool::HostPool;
-// use wapc_pool::HostPoolBuilder;
-// use core::slice::SlicePattern;
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::str::FromStr;
-use wkr_runtime::environment::Environment;
-use wkr_runtime::EnvironmentBuilder;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use wkr_runtime::environment::{Environment, InvocationResult};
 use wkr_runtime::errors::Error;
-// use wkr_runtime::HostCallback;
-// use wkr_runtime::Resource;
-// use wkr_runtime::ResourceTable;
-// use wkr_runtime::WapcHost;
-// use wkr_runtime::WasiParams;
-// use wkr_runtime::wasi::Environment;
-// use std::error::Error;
-// use crate::bridge::fetch::{op_fetch, FetchArgs, FetchResponse};
-// use crate::bridge::crypto::{crypto_get_random_values, crypto_random_uuid, crypto_subtle_digest};
-// use crate::bridge::fetch::FetchResponse;
-// use crate::bridge::database::{ process_database };
-// use crate::bridge::key::CryptoHash;
-use anyhow::Result;
-use serde::ser::StdError;
-use serde::{Deserialize, Serialize};
-use wkr_common::resources::{ResourceTable, Resource};
-use wkr_runtime::wasi::WasiParams;
-use std::fs::read;
-use std::sync::Arc;
-use std::sync::Mutex;
-use wapc_codec::messagepack::{deserialize, serialize};
-// use super::resources::{ Resource, ResourceTable };
-use serde_bytes::ByteBuf;
-use serde_bytes::Bytes;
-// use url::Url;
-// use wkr_fetch::{process_ops};
-
-#[derive(Deserialize, Serialize, Debug)]
-struct EncodeDigestArg {
-    algorithm: String,
-    data: ByteBuf,
-    //   data: Vec<u8>,
+use wkr_runtime::stats::STARTS;
+use metrics::increment_counter;
+
+const DEFAULT_MIN_INSTANCES: usize = 1;
+const DEFAULT_MAX_INSTANCES: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Builds a [`HostPool`]
+#[derive(Debug, Clone)]
+pub struct HostPoolBuilder {
+    min_instances: usize,
+    max_instances: usize,
+    idle_timeout: Duration,
 }
-#[derive(Deserialize, Serialize, Debug)]
-struct GetRandomValuesArg {
-    value: u32,
+
+impl Default for HostPoolBuilder {
+    fn default() -> Self {
+        HostPoolBuilder {
+            min_instances: DEFAULT_MIN_INSTANCES,
+            max_instances: DEFAULT_MAX_INSTANCES,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
 }
 
+impl HostPoolBuilder {
+    pub fn new() -> Self {
+        HostPoolBuilder::default()
+    }
 
+    /// Number of initialized instances kept warm, even when the pool is idle
+    #[must_use]
+    pub fn min_instances(mut self, min_instances: usize) -> Self {
+        self.min_instances = min_instances;
+        self
+    }
 
-struct FetchRequestResource(String);
+    /// Upper bound of instances alive at the same time. Callers wait for an instance to
+    /// be released once it is reached.
+    #[must_use]
+    pub fn max_instances(mut self, max_instances: usize) -> Self {
+        self.max_instances = max_instances.max(1);
+        self
+    }
 
-impl Resource for FetchRequestResource {
-    fn name(&self) -> Cow<str> {
-        "fetchRequest".into()
+    /// How long an instance above `min_instances` can stay idle before being dropped
+    #[must_use]
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
     }
+
+    /// Create the pool and warm up `min_instances` instances of `template`.
+    ///
+    /// `template` doesn't need to be initialized, every instance of the pool is a fresh
+    /// instance of its module, created with [`Environment::try_clone`].
+    pub async fn build(self, template: Environment) -> Result<HostPool, Error> {
+        let min_instances = self.min_instances.min(self.max_instances);
+        let inner = Arc::new(PoolInner {
+            function: template.function_id().to_owned(),
+            template: Mutex::new(template),
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(self.max_instances)),
+            size: AtomicUsize::new(0),
+            min_instances,
+            max_instances: self.max_instances,
+            idle_timeout: self.idle_timeout,
+        });
+
+        for _ in 0..min_instances {
+            let environment = inner.instantiate().await?;
+            inner.release(environment);
+        }
+
+        spawn_reaper(Arc::downgrade(&inner));
+
+        Ok(HostPool { inner })
+    }
+}
+
+/// A pool of initialized [`Environment`]s of the same module, so calls don't pay for
+/// instantiating the module and running its start functions.
+///
+/// The pool grows on demand up to `max_instances` and shrinks back to `min_instances`
+/// when instances stay idle. Instances are dropped when a call fails since the guest may
+/// have been left in an inconsistent state.
+///
+/// Between calls, only the waPC buffers and the resources of an instance are reset: its
+/// linear memory and its globals are kept, so what a call leaves in them is visible to the
+/// next calls of the same instance. Functions must not keep the data of a request in
+/// global state, and callers handling a failure themselves must
+/// [`discard`](PooledEnvironment::discard) the instance.
+#[derive(Clone)]
+pub struct HostPool {
+    inner: Arc<PoolInner>,
 }
 
-// struct FetchClientResource(Client);
-
-// impl Resource for FetchClientResource {
-//     fn name(&self) -> Cow<str> {
-//         "fetchClient".into()
-//     }
-// }
-
-// struct FetchRequestBuilderResource(Arc<Mutex<RequestBuilder>>);
-
-// impl Resource for FetchRequestBuilderResource {
-//     fn name(&self) -> Cow<str> {
-//         "fetchRequestBuilder".into()
-//     }
-// }
-// struct FetchResponseResource(Response);
-
-// impl Resource for FetchResponseResource {
-//     fn name(&self) -> Cow<str> {
-//         "fetchResponse".into()
-//     }
-// }
-
-#[derive(Deserialize, Serialize, Debug)]
-pub struct RegExpRequest {
-    pub pattern: String,
-    pub flag: String,
-    pub input: String,
-    pub last_index: i32,
+struct PoolInner {
+    /// The id of the function of the instances, to label metrics
+    function: String,
+    template: Mutex<Environment>,
+    idle: Mutex<Vec<IdleEnvironment>>,
+    permits: Arc<Semaphore>,
+    size: AtomicUsize,
+    min_instances: usize,
+    max_instances: usize,
+    idle_timeout: Duration,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct Match {
-    pub matches: Vec<String>,
-    pub index: i32,
-    pub last_index: i32,
-    pub input: String,
-    pub indices: Vec<Vec<u32>>,
-    pub groups: HashMap<String, String>,
+struct IdleEnvironment {
+    environment: Environment,
+    since: Instant,
 }
 
-pub fn create_function_engine(path: &str) -> Result<Environment> {
-    // let file = read("/home/dallen/Codes/assemblyscript_test/build/release.wasm")?;
-    let file = read(path)?;
-
-    let builder = EnvironmentBuilder::new(&file);
-    let engine = builder
-        .wasi_params(WasiParams {
-            argv: vec!["mike".to_string(), "jones".to_string()],
-            map_dirs: vec![],
-            env_vars: vec![("POSTGRES".to_string(), "user:password".to_string())],
-            preopened_dirs: vec!["/home/dallen/Codes/wasmtest".to_string()],
+impl HostPool {
+    pub fn builder() -> HostPoolBuilder {
+        HostPoolBuilder::new()
+    }
+
+    /// Take an instance out of the pool, waiting for one to be released when the pool is
+    /// at capacity. The instance goes back to the pool when the guard is dropped.
+    pub async fn get(&self) -> Result<PooledEnvironment, Error> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Error::General(e.to_string()))?;
+
+        let idle = self.inner.idle.lock().unwrap().pop();
+        let (environment, start) = match idle {
+            Some(idle) => (idle.environment, "warm"),
+            None => (self.inner.instantiate().await?, "cold"),
+        };
+        increment_counter!(STARTS, "function" => self.inner.function.clone(), "start" => start);
+
+        Ok(PooledEnvironment {
+            environment: Some(environment),
+            pool: Arc::downgrade(&self.inner),
+            discard: false,
+            _permit: permit,
         })
-        .build()
-        .expect("Cannot create WebAssemblyEngineProvider");
+    }
+
+    /// Call `op` on an instance of the pool
+    pub async fn call(&self, op: &str, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.invoke(op, payload).await.map(|result| result.response)
+    }
+
+    /// Call `op` on an instance of the pool and report the resources it used
+    pub async fn invoke(&self, op: &str, payload: &[u8]) -> Result<InvocationResult, Error> {
+        let mut environment = self.get().await?;
+        let result = environment.invoke(op, payload).await;
+        if result.is_err() {
+            environment.discard();
+        }
+        result
+    }
+
+    /// Number of instances alive, busy or idle
+    pub fn size(&self) -> usize {
+        self.inner.size.load(Ordering::SeqCst)
+    }
+
+    /// Number of instances waiting for a call
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Upper bound of instances alive at the same time
+    pub fn max_instances(&self) -> usize {
+        self.inner.max_instances
+    }
+}
+
+impl PoolInner {
+    async fn instantiate(&self) -> Result<Environment, Error> {
+        // the template is only locked to copy it, instances are initialized concurrently
+        let mut environment = self.template.lock().unwrap().clone_uninitialized()?;
+        environment.init().await?;
+        self.size.fetch_add(1, Ordering::SeqCst);
+        Ok(environment)
+    }
+
+    fn release(&self, mut environment: Environment) {
+        environment.reset();
+        self.idle.lock().unwrap().push(IdleEnvironment {
+            environment,
+            since: Instant::now(),
+        });
+    }
 
-    return Ok(engine);
+    fn forget(&self) {
+        self.size.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Drops the instances idle for longer than `idle_timeout`, down to `min_instances`
+    fn evict_idle(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        // the oldest instances are at the front, the most recently released ones are
+        // handed out first
+        while self.size.load(Ordering::SeqCst) > self.min_instances {
+            match idle.first() {
+                Some(oldest) if oldest.since.elapsed() >= self.idle_timeout => {
+                    idle.remove(0);
+                    self.forget();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Brings the pool back to `min_instances`, e.g. after failed instances were dropped
+    async fn replenish(&self) -> Result<(), Error> {
+        while self.size.load(Ordering::SeqCst) < self.min_instances {
+            let environment = self.instantiate().await?;
+            self.release(environment);
+        }
+        Ok(())
+    }
+}
+
+fn spawn_reaper(pool: Weak<PoolInner>) {
+    let period = match pool.upgrade() {
+        Some(pool) => (pool.idle_timeout / 2).max(Duration::from_secs(1)),
+        None => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => break,
+            };
+            pool.evict_idle();
+            if let Err(e) = pool.replenish().await {
+                tracing::warn!(function = %pool.function, "cannot warm up pool instance: {}", e);
+            }
+        }
+    });
+}
+
+/// An [`Environment`] checked out of a [`HostPool`]
+pub struct PooledEnvironment {
+    environment: Option<Environment>,
+    pool: Weak<PoolInner>,
+    discard: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledEnvironment {
+    /// Drop the instance instead of returning it to the pool
+    pub fn discard(&mut self) {
+        self.discard = true;
+    }
+}
+
+impl Deref for PooledEnvironment {
+    type Target = Environment;
+
+    fn deref(&self) -> &Self::Target {
+        self.environment.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledEnvironment {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.environment.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledEnvironment {
+    fn drop(&mut self) {
+        let environment = match self.environment.take() {
+            Some(environment) => environment,
+            None => return,
+        };
+        if let Some(pool) = self.pool.upgrade() {
+            if self.discard {
+                pool.forget();
+            } else {
+                pool.release(environment);
+            }
+        }
+    }
 }
 
-pub async fn host_callback(
-    id: u64,
-    binding: &str,
-    namespace: &str,
-    operation: &str,
-    payload: &[u8],
-    resource_table: Arc<Mutex<ResourceTable>>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    println!(
-        "Guest {} invoked '{}->{}:{}' with a {} byte payload",
-        id,
-        binding,
-        namespace,
-        operation,
-        payload.len()
-    );
-
-    // match (binding, namespace, operation) {
-    //     ("fetch", _, _) => {
-    //         let result = process_ops(id, binding, namespace, operation, payload, resource_table).await;
-
-    //         return result;
-    //     }
-    //     _ => {}
-    // }
-
-    // Return success with zero-byte payload.
-    Ok(vec![])
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wkr_runtime::builder::EnvironmentBuilder;
+
+    /// A pool of a guest answering every call with an empty response
+    async fn pool(builder: HostPoolBuilder) -> HostPool {
+        let module = wat::parse_str(
+            r#"(module
+                (import "wapc" "__guest_response" (func $response (param i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "__guest_call") (param i32 i32) (result i32)
+                    (call $response (i32.const 0) (i32.const 0))
+                    (i32.const 1))
+            )"#,
+        )
+        .unwrap();
+        let template = EnvironmentBuilder::new(&module).build().unwrap();
+        builder.build(template).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reuse_instances() {
+        let pool = pool(HostPoolBuilder::new().min_instances(1).max_instances(2)).await;
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+
+        pool.call("hello", b"").await.unwrap();
+        pool.call("hello", b"").await.unwrap();
+        // the warm instance served both calls
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        assert_eq!((pool.size(), pool.idle()), (2, 0));
+        drop((first, second));
+        assert_eq!((pool.size(), pool.idle()), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn evict_idle_instances() {
+        let builder = HostPoolBuilder::new().min_instances(1).idle_timeout(Duration::ZERO);
+        let pool = pool(builder).await;
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        drop((first, second));
+        assert_eq!(pool.size(), 2);
+
+        pool.inner.evict_idle();
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn replenish_discarded_instances() {
+        let pool = pool(HostPoolBuilder::new().min_instances(1)).await;
+        let mut environment = pool.get().await.unwrap();
+        environment.discard();
+        drop(environment);
+        assert_eq!((pool.size(), pool.idle()), (0, 0));
+
+        pool.inner.replenish().await.unwrap();
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+    }
 }
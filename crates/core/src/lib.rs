@@ -1,70 +1,82 @@
 
+pub mod config;
+pub mod host_pool;
+
+pub use config::FunctionConfig;
+pub use wkr_runtime::errors;
+pub use wkr_runtime::stats;
+pub use wkr_runtime::environment::Environment;
+pub use wkr_runtime::inspect::{inspect, ModuleInfo};
+pub use wkr_runtime::{wasi::WasiParams, EnvironmentBuilder};
+
+use host_pool::{HostPool, HostPoolBuilder};
+use std::path::{Path, PathBuf};
 use tokio::fs::read;
-use wapc_codec::messagepack::serialize;
 use anyhow::Result;
-use wkr_runtime::{environment::Environment, EnvironmentBuilder, wasi::WasiParams};
 
-pub async fn run() -> Result<()> {
-    let mut environment = create_function_engine("/home/dallen/Codes/assemblyscript/testing/builds/myModule.wasm").await.unwrap();
-    environment.init().await?;
-    // let host = create_function_pool(engine).unwrap();
-    // let store = engine.store;
-    // let engine = wasmtime_provider::WasmtimeEngineProvider::new(&buf, None)?;
-    // let guest = WapcHost::new(
-    //     Box::new(engine),
-    //     // Some(Box::new(host_callback)),
-    //     None,
-    // ).await.unwrap();
+/// The directory compiled modules are cached in: `WKR_CACHE_DIR`, or `.wkr/cache`
+pub fn module_cache_dir() -> PathBuf {
+    std::env::var_os("WKR_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(".wkr/cache"))
+}
+
+/// Compiles the module at `path` into the module cache, ahead of its deployment.
+/// Returns the path of the compiled artifact.
+pub async fn compile_function(path: &str) -> Result<PathBuf> {
+    let file = read(path).await?;
+    compile_module(&file)
+}
+
+/// Compiles `module` into the module cache, failing when it isn't a valid module.
+/// Returns the path of the compiled artifact.
+pub fn compile_module(module: &[u8]) -> Result<PathBuf> {
+    let artifact = EnvironmentBuilder::new(module)
+        .cache_dir(module_cache_dir())
+        .precompile()?;
 
-    
-    let callresult = environment.call("test", &serialize("hello world").unwrap()).await.unwrap();
-    // let result: String = deserialize(&callresult).unwrap();
-    // conver to String
-    let result = String::from_utf8(callresult).unwrap();
-    // assert_eq!(result, "hello world");
-    Ok(())
+    Ok(artifact)
 }
 
-pub async fn create_function_engine(path: &str) -> Result<Environment> {
-    // let file = read("/home/dallen/Codes/assemblyscript_test/build/release.wasm")?;
+/// Creates the environment of the module at `path`, configured by `config`. Use
+/// [`FunctionConfig::for_module`] to read the manifest deployed next to the module.
+pub async fn create_function_engine(path: &str, config: &FunctionConfig) -> Result<Environment> {
     let file = read(path).await?;
+    let module_name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
 
-    let builder = EnvironmentBuilder::new(&file);
-    let engine = builder
-        .wasi_params(WasiParams {
-            argv: vec!["mike".to_string(), "jones".to_string()],
-            map_dirs: vec![
-                // ("mike".to_string(), "/home/dallen/Codes/wasmtest/wasi/".to_string()),
-                // ("mike2".to_string(), "/home/dallen/Codes/wasmtest/wasi/folder2/".to_string()),
-                ],
-            // map_dirs: vec![],
-            env_vars: vec![("POSTGRES".to_string(), "user:password".to_string())],
-            // preopened_dirs: vec!["/home/dallen/Codes/wasmtest/wasi".to_string()],
-            preopened_dirs: vec![],
-        })
-        .build()
-        .expect("Cannot create WebAssemblyEngineProvider");
+    let builder = EnvironmentBuilder::new(&file).cache_dir(module_cache_dir());
+    let engine = config.apply(builder, &module_name).build()?;
 
-    return Ok(engine);
+    Ok(engine)
 }
 
-pub async fn create_function_engine_with_bytes(module: Vec<u8>) -> Result<Environment> {
+/// Creates the environment of the `name` function from its `module`, configured by
+/// `config`. The guest output is forwarded to the logs of the host.
+pub async fn create_function_engine_with_bytes(
+    name: &str,
+    module: Vec<u8>,
+    config: &FunctionConfig,
+) -> Result<Environment> {
+    let builder = EnvironmentBuilder::new(&module)
+        .cache_dir(module_cache_dir())
+        .function_id(name)
+        .forward_stdio(true);
+    let engine = config.apply(builder, name).build()?;
 
-    let builder = EnvironmentBuilder::new(&module);
-    let engine = builder
-        .wasi_params(WasiParams {
-            argv: vec!["mike".to_string(), "jones".to_string()],
-            map_dirs: vec![
-                // ("mike".to_string(), "/home/dallen/Codes/wasmtest/wasi/".to_string()),
-                // ("mike2".to_string(), "/home/dallen/Codes/wasmtest/wasi/folder2/".to_string()),
-                ],
-            // map_dirs: vec![],
-            env_vars: vec![("POSTGRES".to_string(), "user:password".to_string())],
-            preopened_dirs: vec!["/home/dallen/Codes/wasmtest/wasi".to_string()],
-            // preopened_dirs: vec![],
-        })
-        .build()
-        .expect("Cannot create WebAssemblyEngineProvider");
+    Ok(engine)
+}
 
-    return Ok(engine);
-}
\ No newline at end of file
+pub async fn create_function_pool(
+    name: &str,
+    module: Vec<u8>,
+    config: &FunctionConfig,
+    builder: HostPoolBuilder,
+) -> Result<HostPool> {
+    let template = create_function_engine_with_bytes(name, module, config).await?;
+    let pool = builder.build(template).await?;
+
+    Ok(pool)
+}
@@ -26,12 +26,11 @@ pub struct ExecuteResult {
     time: Option<f64>,
 }
 
+/// Opens a connection to the database at `url`, the one checked against the permissions
+/// of the guest
 pub async fn connected_to_database(url:String)->Result<Quaint> {
-    println!("Connected to database: {}", url);
-    // let conn = Quaint::new(&url).await?;
-    // let conn = Quaint::new_in_memory()?;
-    let conn = Quaint::new("file:///home/dallen/Codes/tikvtest/Chinook.db").await?;
-    // println!("Connected to database: {}", url);
+    tracing::debug!("connecting to database {}", url);
+    let conn = Quaint::new(&url).await?;
 
     Ok(conn)
 }
@@ -193,8 +192,7 @@ fn read_from_msgpack(buf: &mut Vec<u8>)-> Result<Vec<Value>> {
             Marker::FixArray(_size) => {
                 let val = rmp::decode::read_array_len(&mut cur)?;
                 // params.push(Value::Bytes(None));
-                println!("FixArray: {:?}", val );
-                println!("----------------------------------")
+                tracing::warn!(len = val, "array parameters are not supported");
 
                 // let vv = vec![];
                 // for _ in 0..val {
@@ -207,7 +205,7 @@ fn read_from_msgpack(buf: &mut Vec<u8>)-> Result<Vec<Value>> {
                 params.push(Value::Bytes(None));
             }
             _ => {
-                println!("Unknown: {:?}", ext);
+                tracing::warn!("unsupported parameter type: {:?}", ext);
             }
         }
     }
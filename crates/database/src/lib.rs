@@ -1,7 +1,7 @@
 pub mod database;
 use database::{connected_to_database, execute, query};
 use quaint::single::Quaint;
-use wkr_common::{resources::{ResourceTable, Resource}};
+use wkr_common::{resources::{type_error, ResourceTable, Resource}};
 use std::{sync::{Arc}, borrow::Cow};
 use serde::{Deserialize, Serialize};
 use wapc_codec::messagepack::{deserialize, serialize};
@@ -107,8 +107,10 @@ pub async fn process_database_ops(
 
             Ok(response)
         },
-        _ => {
-            Ok(vec![])
-        }
+        _ => Err(type_error(format!(
+            "unknown host call `{}:{}:{}`",
+            binding, namespace, operation
+        ))
+        .into()),
     }
 }
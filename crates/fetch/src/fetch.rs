@@ -49,6 +49,9 @@ use tokio_stream::Stream;
 use futures::StreamExt;
 use tokio_util::io::StreamReader;
 use wapc_codec::messagepack::{deserialize, serialize};
+use tracing::Instrument;
+use crate::trace::inject_trace_context;
+use crate::stream::ResponseBodyResource;
 
 /// Returned by resource read/write/shutdown methods
 pub type AsyncResult<T> = Pin<Box<dyn Future<Output = Result<T, Error>>>>;
@@ -178,6 +181,12 @@ pub async fn op_fetch(
 ) -> Result<FetchReturn, Error> {
     let method = Method::from_bytes(&args.method.as_bytes()).unwrap();
     let url = Url::parse(&args.url).unwrap();
+    let span = tracing::info_span!(
+        "fetch",
+        otel.kind = "client",
+        http.method = %method,
+        http.url = %url,
+    );
 
     let client = Client::new();
     let mut request = client.request(method.clone(), url);
@@ -197,7 +206,6 @@ pub async fn op_fetch(
                 request = request.body(Body::wrap_stream(ReceiverStream::new(rx)));
 
                 let request_body_rid = resource_table.add(FetchRequestBodyResource2(tx));
-                println!("request_body_rid: {}", request_body_rid);
                 // let request_body_rid =
                 //   resource_table.add(FetchRequestBodyResource {
                 //     body: AsyncRefCell::new(tx),
@@ -239,6 +247,7 @@ pub async fn op_fetch(
         // If httpRequest’s header list contains `Range`, then append (`Accept-Encoding`, `identity`)
         header_map.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
     }
+    inject_trace_context(&span, &mut header_map);
     request = request.headers(header_map);
 
     // let res = request.send().await.unwrap();
@@ -264,12 +273,11 @@ pub async fn op_fetch(
     // let request_rid = resource_table
     // .add(StringResource(String::from("hello")));
 
-    let fut = request.send();
+    let fut = request.send().instrument(span);
 
     let request_rid = resource_table
       .add(FetchRequestResource(Box::pin(fut)));
 
-      println!("request_rid: {}", request_rid);
 
     // let cancel_handle_rid = resource_table.add(FetchCancelHandle(cancel_handle));
 
@@ -354,10 +362,10 @@ pub async fn op_fetch_send(
     })
   }
 
-struct FetchRequestBodyResource2(mpsc::Sender<std::io::Result<bytes::Bytes>>);
+pub(crate) struct FetchRequestBodyResource2(pub(crate) mpsc::Sender<std::io::Result<bytes::Bytes>>);
 impl Resource for FetchRequestBodyResource2 {
     fn name(&self) -> Cow<str> {
-        "database".into()
+        "fetchRequestBody".into()
     }
 }
 
@@ -366,7 +374,7 @@ type BytesStream =
 // type BytesStream =
 //   Pin<Box<dyn Stream<Item = Result<bytes::Bytes, std::io::Error>> + Unpin>>;
 // struct FetchResponseBodyResource2(BytesStream);
-struct FetchResponseBodyResource2(tokio::sync::mpsc::Receiver<Result<bytes::Bytes, std::io::Error>>);
+pub(crate) struct FetchResponseBodyResource2(pub(crate) tokio::sync::mpsc::Receiver<Result<bytes::Bytes, std::io::Error>>);
 // struct FetchResponseBodyResource2(StreamReader<BytesStream, bytes::Bytes>);
 
 impl Resource for FetchResponseBodyResource2 {
@@ -399,17 +407,19 @@ pub async fn op_fetch_read_body(
     mut resource_table: MutexGuard<'_, ResourceTable>,
     args: FetchReadBody,
   ) -> anyhow::Result<FetchReadBodyReturn> {
-    println!("op_fetch_read_body3333333: {}", args.rid);
     let response_body = resource_table
       .take::<FetchResponseBodyResource2>(args.rid)?;
   
     let mut response_body = Arc::try_unwrap(response_body)
-        .ok()
-        .expect("multiple op_fetch_read_body ongoing");
-    let mut response_body = response_body.0;
+        .map_err(|_| type_error("the body is already being read"))?
+        .0;
 
-    let bytes = response_body.recv().await.unwrap().unwrap();
-    println!("bytes!!!!!!!!!!: {}", bytes.len());
+    // a body whose writer is gone reads as ended
+    let bytes = response_body
+        .recv()
+        .await
+        .unwrap_or_else(|| Ok(bytes::Bytes::new()))?;
+    tracing::trace!(rid = args.rid, size = bytes.len(), "read body chunk");
 
     // // let buffer = &mut vec![];
     // let response_body = &mut response_body.0;
@@ -419,7 +429,6 @@ pub async fn op_fetch_read_body(
 
     let rid = resource_table.add(FetchResponseBodyResource2(response_body));
     // let rid = resource_table.add(response_body);
-    println!("op_fetch_read_body!!!!!!!!!!: {}", rid);
   
     Ok(FetchReadBodyReturn{
         chunk: bytes.to_vec(),
@@ -448,23 +457,39 @@ pub struct FetchWriteBodyReturn{
     mut resource_table: MutexGuard<'_, ResourceTable>,
     args: FetchWriteBody,
   ) -> anyhow::Result<FetchWriteBodyReturn> {
-    println!("op_fetch_read_body3333333: {}", args.rid);
-    let resquest_body = resource_table
-      .take::<FetchRequestBodyResource2>(args.rid)?;
-  
-    let resquest_body = Arc::try_unwrap(resquest_body)
-        .ok()
-        .expect("multiple op_fetch_read_body ongoing");
-    let resquest_body = resquest_body.0;
+    tracing::trace!(rid = args.rid, size = args.chunk.len(), "write body chunk");
+    if let Ok(response_body) = resource_table.get::<ResponseBodyResource>(args.rid) {
+        response_body.check_writable()?;
+    }
+    let already_written = || type_error("the body is already being written");
+    // the body of a fetch request, or the body of a streamed response along with the flag
+    // of its head
+    let request_body = resource_table.take::<FetchRequestBodyResource2>(args.rid);
+    let (resquest_body, head_sent) = match request_body {
+        Ok(body) => (Arc::try_unwrap(body).map_err(|_| already_written())?.0, None),
+        Err(_) => {
+            let body = resource_table.take::<ResponseBodyResource>(args.rid)?;
+            let body = Arc::try_unwrap(body).map_err(|_| already_written())?;
+            (body.body, Some(body.head_sent))
+        }
+    };
 
     let chunk = bytes::Bytes::copy_from_slice(&args.chunk);
-    let _bytes = resquest_body.send(Ok(chunk)).await.unwrap();
+    resquest_body
+        .send(Ok(chunk))
+        .await
+        .map_err(|_| type_error("the body is no longer read"))?;
     // println!("bytes!!!!!!!!!!: {}", bytes.len());
 
 
-    let rid = resource_table.add(FetchRequestBodyResource2(resquest_body));
+    let rid = match head_sent {
+        Some(head_sent) => resource_table.add(ResponseBodyResource {
+            body: resquest_body,
+            head_sent,
+        }),
+        None => resource_table.add(FetchRequestBodyResource2(resquest_body)),
+    };
     // let rid = resource_table.add(resquest_body);
-    println!("op_fetch_read_body!!!!!!!!!!: {}", rid);
   
     Ok(FetchWriteBodyReturn{
         size: args.size as u64,
@@ -1,12 +1,19 @@
 mod fetch;
+mod stream;
+mod trace;
 
 use anyhow::Error;
 use fetch::{FetchRequest, op_fetch, op_fetch_send, op_fetch_read_body, FetchReadBodyReturn, FetchReadBody, FetchWriteBody, op_fetch_write_body};
-use wkr_common::resources::ResourceTable;
+use stream::{op_fetch_close, op_fetch_respond, FetchRespond};
+use wkr_common::resources::{type_error, ResourceTable};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use wapc_codec::messagepack::{deserialize, serialize};
 
+pub use stream::{
+    add_readable_body, add_streamed_response, ResponseHead, StreamedResponse, BODY_CHANNEL_CAPACITY,
+};
+
 pub async fn process_ops(
     _id: u64,
     binding: &str,
@@ -19,7 +26,6 @@ pub async fn process_ops(
         ("fetch", "init", _) => {
             let state = resource_table.lock().await;
             let fetch_args: FetchRequest = deserialize(payload).unwrap();
-            println!("fetch_args: {:?}", fetch_args );
             let resp = op_fetch(state, fetch_args).await.unwrap();
             let fetch_response = serialize(&resp).unwrap();
 
@@ -36,7 +42,7 @@ pub async fn process_ops(
         ("fetch", "read_body", _) => {
             let state = resource_table.lock().await;
             let fetch_args: FetchReadBody = deserialize(payload).unwrap();
-            let fetch_response = op_fetch_read_body(state, fetch_args).await.unwrap();
+            let fetch_response = op_fetch_read_body(state, fetch_args).await?;
             let fetch_response = serialize(&fetch_response).unwrap();
 
             return Ok(fetch_response);
@@ -44,13 +50,27 @@ pub async fn process_ops(
         ("fetch", "write_body", _) => {
             let state = resource_table.lock().await;
             let fetch_args: FetchWriteBody = deserialize(payload).unwrap();
-            let resp = op_fetch_write_body(state, fetch_args).await.unwrap();
+            let resp = op_fetch_write_body(state, fetch_args).await?;
             let fetch_response = serialize(&resp).unwrap();
 
             return Ok(fetch_response);
         }
+        ("fetch", "respond", _) => {
+            let state = resource_table.lock().await;
+            let fetch_args: FetchRespond = deserialize(payload)?;
+            op_fetch_respond(state, fetch_args)?;
+
+            return Ok(vec![]);
+        }
+        ("fetch", "close", _) => {
+            let state = resource_table.lock().await;
+            let rid: u32 = deserialize(payload)?;
+            op_fetch_close(state, rid)?;
+
+            return Ok(vec![]);
+        }
         _ => {}
     }
 
-    Ok(vec![])
+    Err(type_error(format!("unknown host call `{}:{}:{}`", binding, namespace, operation)).into())
 }
@@ -0,0 +1,135 @@
+use std::borrow::Cow;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, oneshot, MutexGuard};
+use wkr_common::resources::{type_error, Resource, ResourceId, ResourceTable};
+
+use crate::fetch::FetchResponseBodyResource2;
+
+/// Chunks buffered by a streamed body before its writer waits for the reader
+pub const BODY_CHANNEL_CAPACITY: usize = 1;
+
+/// The status and the headers of a streamed response, sent by the guest before its body
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseHead {
+    pub status: u16,
+    /// The headers in order, duplicates included, their values as bytes
+    pub headers: Vec<(String, ByteBuf)>,
+}
+
+struct ResponseHeadResource {
+    head: oneshot::Sender<ResponseHead>,
+    sent: Arc<AtomicBool>,
+}
+
+impl Resource for ResponseHeadResource {
+    fn name(&self) -> Cow<str> {
+        "responseHead".into()
+    }
+}
+
+/// The body of a streamed response, written by the guest with `fetch:write_body` once the
+/// head of the response is sent
+pub(crate) struct ResponseBodyResource {
+    pub(crate) body: mpsc::Sender<io::Result<bytes::Bytes>>,
+    pub(crate) head_sent: Arc<AtomicBool>,
+}
+
+impl ResponseBodyResource {
+    /// Fails until the head of the response is sent: nothing reads the body before, so
+    /// a guest writing it first would wait forever
+    pub(crate) fn check_writable(&self) -> anyhow::Result<()> {
+        if self.head_sent.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(type_error("the head of the response must be sent with fetch:respond first"))
+        }
+    }
+}
+
+impl Resource for ResponseBodyResource {
+    fn name(&self) -> Cow<str> {
+        "responseBody".into()
+    }
+}
+
+/// Exposes `body` to the guest as a readable body, read chunk by chunk with
+/// `fetch:read_body`. An empty chunk marks the end of the body.
+pub fn add_readable_body(
+    resource_table: &mut ResourceTable,
+    body: mpsc::Receiver<io::Result<bytes::Bytes>>,
+) -> ResourceId {
+    resource_table.add(FetchResponseBodyResource2(body))
+}
+
+/// The resources of a response streamed by the guest, and the receivers of what the guest
+/// sends through them
+pub struct StreamedResponse {
+    /// Where the guest sends the head of the response with `fetch:respond`
+    pub head_rid: ResourceId,
+    pub head: oneshot::Receiver<ResponseHead>,
+    /// The body the guest writes with `fetch:write_body` once the head is sent. It ends
+    /// when the guest closes it with `fetch:close`, or when the resource table is dropped.
+    pub body_rid: ResourceId,
+    pub body: mpsc::Receiver<io::Result<bytes::Bytes>>,
+}
+
+/// Creates the head and the body of a response streamed by the guest
+pub fn add_streamed_response(resource_table: &mut ResourceTable) -> StreamedResponse {
+    let (head_tx, head) = oneshot::channel();
+    let (body_tx, body) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+    let sent = Arc::new(AtomicBool::new(false));
+    let head_rid = resource_table.add(ResponseHeadResource {
+        head: head_tx,
+        sent: sent.clone(),
+    });
+    let body_rid = resource_table.add(ResponseBodyResource {
+        body: body_tx,
+        head_sent: sent,
+    });
+    StreamedResponse {
+        head_rid,
+        head,
+        body_rid,
+        body,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchRespond {
+    rid: ResourceId,
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, ByteBuf)>,
+}
+
+/// Sends the head of a streamed response, once
+pub fn op_fetch_respond(
+    mut resource_table: MutexGuard<'_, ResourceTable>,
+    args: FetchRespond,
+) -> anyhow::Result<()> {
+    let head = resource_table.take::<ResponseHeadResource>(args.rid)?;
+    let head = Arc::try_unwrap(head)
+        .map_err(|_| type_error("the response is already being sent"))?;
+
+    head.head
+        .send(ResponseHead {
+            status: args.status,
+            headers: args.headers,
+        })
+        .map_err(|_| type_error("the response is no longer awaited"))?;
+    head.sent.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Closes a resource, which ends a body being written
+pub fn op_fetch_close(
+    mut resource_table: MutexGuard<'_, ResourceTable>,
+    rid: ResourceId,
+) -> anyhow::Result<()> {
+    resource_table.close(rid)
+}
@@ -0,0 +1,28 @@
+use http::header::{HeaderName, HeaderValue};
+use http::HeaderMap;
+use opentelemetry::global;
+use opentelemetry::propagation::Injector;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Adds the `traceparent` and `tracestate` headers of `span` to an outgoing request, so
+/// the called service continues the trace. Those set by the guest are replaced.
+pub(crate) fn inject_trace_context(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
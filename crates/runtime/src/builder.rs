@@ -1,7 +1,14 @@
-use crate::errors::Result;
+use crate::errors::{Error, Result};
 // use crate::Environment;
 use crate::wasi::WasiParams;
-use crate::environment::{EpochDeadlines, Environment};
+use crate::environment::{EpochDeadlines, Environment, FuelBudgets};
+use crate::host_binding::{HostBinding, HostBindings};
+use crate::limits::ResourceLimits;
+use crate::module_cache::ModuleCache;
+use crate::permissions::Permissions;
+use crate::stdio::StdioCapture;
+use std::path::PathBuf;
+use std::time::Duration;
 
 
 #[derive(Default)]
@@ -9,6 +16,18 @@ pub struct EnvironmentBuilder<'a> {
   module_bytes: &'a [u8],
   wasi_params: Option<WasiParams>,
   epoch_deadlines: Option<EpochDeadlines>,
+  epoch_tick: Option<Duration>,
+  engine: Option<wasmtime::Engine>,
+  debug_info: Option<bool>,
+  allow_unknown_imports: bool,
+  fuel_budgets: Option<FuelBudgets>,
+  resource_limits: ResourceLimits,
+  stdio_capture: Option<StdioCapture>,
+  host_bindings: HostBindings,
+  function_id: String,
+  function_version: String,
+  permissions: Option<Permissions>,
+  cache_dir: Option<PathBuf>,
 }
 
 impl<'a> EnvironmentBuilder<'a> {
@@ -18,6 +37,7 @@ impl<'a> EnvironmentBuilder<'a> {
   pub fn new(module_bytes: &'a [u8]) -> Self {
     EnvironmentBuilder {
       module_bytes,
+      stdio_capture: Some(StdioCapture::default()),
       ..Default::default()
     }
   }
@@ -28,6 +48,74 @@ impl<'a> EnvironmentBuilder<'a> {
     self
   }
 
+  /// Register a [`HostBinding`] handling every `__host_call` made to `binding`.
+  ///
+  /// The built-in `fetch` and `database` bindings are registered by default, registering
+  /// a handler with the same name replaces them.
+  #[must_use]
+  pub fn host_binding<B: HostBinding + 'static>(mut self, binding: &str, handler: B) -> Self {
+    self.host_bindings.register(binding, handler);
+    self
+  }
+
+  /// Register a [`HostBinding`] handling the `__host_call`s made to `binding:namespace`
+  #[must_use]
+  pub fn host_binding_namespace<B: HostBinding + 'static>(mut self, binding: &str, namespace: &str, handler: B) -> Self {
+    self.host_bindings.register_namespace(binding, namespace, handler);
+    self
+  }
+
+  /// Register a [`HostBinding`] handling the `binding:namespace:operation` `__host_call`
+  #[must_use]
+  pub fn host_binding_operation<B: HostBinding + 'static>(
+    mut self,
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    handler: B,
+  ) -> Self {
+    self.host_bindings.register_operation(binding, namespace, operation, handler);
+    self
+  }
+
+  /// Replace the whole host binding registry, built-in bindings included
+  #[must_use]
+  pub fn host_bindings(mut self, host_bindings: HostBindings) -> Self {
+    self.host_bindings = host_bindings;
+    self
+  }
+
+  /// The id of the function implemented by the module, used to attribute the logs
+  /// of the guest
+  #[must_use]
+  pub fn function_id<T: Into<String>>(mut self, function_id: T) -> Self {
+    self.function_id = function_id.into();
+    self
+  }
+
+  /// The version of the function implemented by the module, recorded on the span of
+  /// each invocation
+  #[must_use]
+  pub fn function_version<T: Into<String>>(mut self, function_version: T) -> Self {
+    self.function_version = function_version.into();
+    self
+  }
+
+  /// Restrict the host calls the guest can perform. Every host call is allowed when no
+  /// permissions are provided.
+  #[must_use]
+  pub fn permissions(mut self, permissions: Permissions) -> Self {
+    self.permissions = Some(permissions);
+    self
+  }
+
+  /// Load the compiled module from, and store it into, the [`ModuleCache`] in `cache_dir`
+  /// instead of compiling it on every build
+  #[must_use]
+  pub fn cache_dir<P: Into<PathBuf>>(mut self, cache_dir: P) -> Self {
+    self.cache_dir = Some(cache_dir.into());
+    self
+  }
 
   /// Enable Wasmtime [epoch-based interruptions](wasmtime::Config::epoch_interruption) and set
   /// the deadlines to be enforced
@@ -43,10 +131,11 @@ impl<'a> EnvironmentBuilder<'a> {
   /// Both these limits are expressed using the number of ticks that are allowed before the
   /// WebAssembly execution is interrupted.
   /// It's up to the embedder of waPC to define how much time a single tick is granted. This could
-  /// be 1 second, 10 nanoseconds, or whatever the user prefers.
+  /// be 1 second, 10 nanoseconds, or whatever the user prefers: see
+  /// [`EnvironmentBuilder::epoch_tick`] to let the runtime increment the epoch.
   ///
   /// **Warning:** when providing an instance of `wasmtime::Engine` via the
-  /// `Environment::engine` helper, ensure the `wasmtime::Engine`
+  /// [`EnvironmentBuilder::engine`] helper, ensure the `wasmtime::Engine`
   /// has been created with the `epoch_interruption` feature enabled
   #[must_use]
   pub fn enable_epoch_interruptions(mut self, wapc_init_deadline: u64, wapc_func_deadline: u64) -> Self {
@@ -57,20 +146,180 @@ impl<'a> EnvironmentBuilder<'a> {
     self
   }
 
-  /// Create a `Environment` instance
-  pub fn build(&self) -> Result<Environment> {
-    
+  /// Maximum size, in bytes, each linear memory of the guest can grow to
+  ///
+  /// A guest hitting the limit fails with
+  /// [`Error::MemoryLimitExceeded`](crate::errors::Error::MemoryLimitExceeded).
+  #[must_use]
+  pub fn memory_limit(mut self, bytes: usize) -> Self {
+    self.resource_limits.memory_size = Some(bytes);
+    self
+  }
+
+  /// Maximum number of elements each table of the guest can grow to
+  #[must_use]
+  pub fn table_elements_limit(mut self, elements: u32) -> Self {
+    self.resource_limits.table_elements = Some(elements);
+    self
+  }
+
+  /// Maximum number of instances alive in the store of the environment
+  #[must_use]
+  pub fn instances_limit(mut self, instances: usize) -> Self {
+    self.resource_limits.instances = Some(instances);
+    self
+  }
+
+  /// Let the runtime increment the engine epoch every `tick`, so the deadlines set with
+  /// [`EnvironmentBuilder::enable_epoch_interruptions`] expire without the embedder
+  /// driving the epoch. Ignored when epoch interruptions are not enabled.
+  ///
+  /// A single ticker runs per engine: environments sharing an engine provided with
+  /// [`EnvironmentBuilder::engine`] share the ticker started by the first of them.
+  #[must_use]
+  pub fn epoch_tick(mut self, tick: Duration) -> Self {
+    self.epoch_tick = Some(tick);
+    self
+  }
+
+  /// Emit native debug info for the compiled code and register it with the debugger
+  /// interface of the host, so guests can be debugged with GDB or LLDB. Disabled by
+  /// default.
+  ///
+  /// The backtraces attached to the runtime errors don't need it: their frames are always
+  /// resolved with the DWARF data of the module when present, and with its name section
+  /// otherwise.
+  #[must_use]
+  pub fn debug_info(mut self, debug_info: bool) -> Self {
+    self.debug_info = Some(debug_info);
+    self
+  }
+
+  /// Link the functions the module imports but the host doesn't provide to stubs that
+  /// trap when called, instead of failing the build with
+  /// [`Error::UnsupportedImport`](crate::errors::Error::UnsupportedImport). Lets modules
+  /// with optional host functions load.
+  #[must_use]
+  pub fn allow_unknown_imports(mut self, allow: bool) -> Self {
+    self.allow_unknown_imports = allow;
+    self
+  }
+
+  /// Compile the module with the given engine instead of creating one per build
+  #[must_use]
+  pub fn engine(mut self, engine: wasmtime::Engine) -> Self {
+    self.engine = Some(engine);
+    self
+  }
+
+  /// Keep up to `limit` bytes per stream and per invocation of the stdout and stderr of
+  /// the guest, 64 KiB by default.
+  ///
+  /// The stdio of the guest is captured in memory unless [`inherit_stdio`] is called, and
+  /// its output is returned with each
+  /// [`InvocationResult`](crate::environment::InvocationResult).
+  ///
+  /// [`inherit_stdio`]: EnvironmentBuilder::inherit_stdio
+  #[must_use]
+  pub fn capture_stdio(mut self, limit: usize) -> Self {
+    self.stdio_capture.get_or_insert_with(StdioCapture::default).limit = limit;
+    self
+  }
+
+  /// Emit the captured guest output as `tracing` events tagged with the function id and
+  /// the request id. Enables the capture with the default limit if needed.
+  #[must_use]
+  pub fn forward_stdio(mut self, forward: bool) -> Self {
+    self.stdio_capture.get_or_insert_with(StdioCapture::default).forward = forward;
+    self
+  }
+
+  /// Let the guest write to the stdout and stderr of the host and read its stdin, instead
+  /// of capturing its stdio. Meant for command line tools, not for servers where the
+  /// output of concurrent calls would be mixed.
+  #[must_use]
+  pub fn inherit_stdio(mut self) -> Self {
+    self.stdio_capture = None;
+    self
+  }
+
+  /// Limit the amount of fuel, roughly the number of instructions, the guest can burn
+  ///
+  /// * `wapc_init_budget`: the fuel available to the waPC initialization code, the
+  ///   `wapc_init`/`_start` functions
+  /// * `wapc_func_budget`: the fuel available to each call of a waPC guest function
+  ///
+  /// A guest running out of fuel fails with
+  /// [`Error::FuelExhausted`](crate::errors::Error::FuelExhausted). Without budgets, fuel is
+  /// only used to make the guest yield to the async executor.
+  #[must_use]
+  pub fn fuel_budgets(mut self, wapc_init_budget: u64, wapc_func_budget: u64) -> Self {
+    self.fuel_budgets = Some(FuelBudgets {
+      wapc_init: wapc_init_budget,
+      wapc_func: wapc_func_budget,
+    });
+    self
+  }
+
+  /// The engine modules are compiled with: the one provided, or a new one configured
+  /// according to the builder
+  pub fn build_engine(&self) -> Result<wasmtime::Engine> {
+    if let Some(engine) = &self.engine {
+      return Ok(engine.clone());
+    }
+
     let mut config = wasmtime::Config::default();
     config.async_support(true);
     config.consume_fuel(true);
+
+    config.debug_info(self.debug_info.unwrap_or(false));
+    config.wasm_backtrace(true);
+    config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
     
     if self.epoch_deadlines.is_some() {
       config.epoch_interruption(true);
     }
 
-    let engine = wasmtime::Engine::new(&config)?;
-    let mut provider = Environment::new_with_engine(self.module_bytes, engine, self.wasi_params.clone())?;
+    Ok(wasmtime::Engine::new(&config)?)
+  }
+
+  /// Compile the module into the cache directory ahead of time, so the next builds with the
+  /// same configuration don't need to compile it. Returns the path of the artifact.
+  pub fn precompile(&self) -> Result<PathBuf> {
+    let cache_dir = self
+      .cache_dir
+      .as_ref()
+      .ok_or_else(|| Error::General("no cache directory configured".to_owned()))?;
+    ModuleCache::new(cache_dir).compile(&self.build_engine()?, self.module_bytes)
+  }
+
+  /// Create a `Environment` instance
+  ///
+  /// With an [`EnvironmentBuilder::epoch_tick`], this starts the epoch ticker on the current
+  /// Tokio runtime, and fails when called outside of one.
+  pub fn build(&self) -> Result<Environment> {
+    let engine = self.build_engine()?;
+    let module = match &self.cache_dir {
+      Some(cache_dir) => ModuleCache::new(cache_dir).load(&engine, self.module_bytes)?,
+      None => wasmtime::Module::new(&engine, self.module_bytes)?,
+    };
+    let mut provider = Environment::new_with_module(
+      module,
+      engine,
+      self.wasi_params.clone(),
+      self.host_bindings.clone(),
+      self.allow_unknown_imports,
+    )?;
     provider.epoch_deadlines = self.epoch_deadlines;
+    if let (Some(_), Some(tick)) = (self.epoch_deadlines, self.epoch_tick) {
+      provider.start_epoch_ticker(tick)?;
+    }
+    provider.fuel_budgets = self.fuel_budgets;
+    provider.set_function_id(self.function_id.clone());
+    provider.set_function_version(self.function_version.clone());
+    provider.set_permissions(self.permissions.clone());
+    provider.set_resource_limits(self.resource_limits);
+    provider.set_stdio_capture(self.stdio_capture);
 
     Ok(provider)
   }
@@ -1,13 +1,34 @@
 use wasmtime::{
-    AsContext, Caller, Func, FuncType, Memory, StoreContext, Val, ValType,
+    AsContext, Caller, FuncType, Linker, Memory, StoreContext, Val, ValType,
 };
+use crate::common::abi;
+use crate::environment::HOST_NAMESPACE;
 use crate::environment_state::EnvironmentState;
+use crate::errors::Result;
+use crate::stats;
+use std::time::Instant;
+use tracing::Instrument;
+use wkr_common::resources::permission_denied;
+
+/// Defines the waPC host functions in `linker`, under the [`HOST_NAMESPACE`] namespace
+pub(crate) fn add_to_linker(linker: &mut Linker<EnvironmentState>) -> Result<()> {
+    guest_request_func(linker)?;
+    console_log_func(linker)?;
+    host_call_func(linker)?;
+    host_response_func(linker)?;
+    host_response_len_func(linker)?;
+    guest_response_func(linker)?;
+    guest_error_func(linker)?;
+    host_error_func(linker)?;
+    host_error_len_func(linker)?;
+    Ok(())
+}
 
 pub(crate) fn guest_request_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32, ValType::I32], vec![]);
-    Func::new_async(store, callback_type, |mut caller, params, _results| {
+    linker.func_new_async(HOST_NAMESPACE, abi::GUEST_REQUEST_FN, callback_type, |mut caller, params, _results| {
         Box::new(async move {
             let op_ptr = params[0].i32();
             let ptr = params[1].i32();
@@ -27,16 +48,18 @@ pub(crate) fn guest_request_func(
             }
             Ok(())
         })
-    })
+    })?;
+    Ok(())
 }
 
 pub(crate) fn console_log_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32, ValType::I32], vec![]);
 
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_CONSOLE_LOG,
         callback_type,
         |mut caller, params: &[Val], _results: &mut [Val]| {
             Box::new(async move {
@@ -46,18 +69,18 @@ pub(crate) fn console_log_func(
                 let vec =
                     get_vec_from_memory(caller.as_context(), memory, ptr.unwrap(), len.unwrap());
 
-                let msg = std::str::from_utf8(&vec).unwrap();
-                let host = caller.data();
-                host.do_console_log(msg);
+                let msg = String::from_utf8_lossy(&vec);
+                caller.data_mut().do_console_log(&msg);
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn host_call_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(
         vec![
             ValType::I32,
@@ -72,8 +95,9 @@ pub(crate) fn host_call_func(
         vec![ValType::I32],
     );
     // let host:Arc<Mutex<EnvironmentState>>  = Arc::clone(&host);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_CALL,
         callback_type,
         |mut caller, params: &[Val], results: &mut [Val]| {
             // let host:Arc<Mutex<EnvironmentState>> = host.clone();
@@ -123,8 +147,36 @@ pub(crate) fn host_call_func(
                 //trace!("Guest {} invoking host operation", id, op);
                 let data = caller.data();
                 let id = data.id;
+                if let Err(e) = data.check_host_call(bd, ns, op, &vec) {
+                    warn!("Guest {} ({}, request {}): {}", data.function_id, id, data.request_id, e);
+                    data.set_host_error(permission_denied(e.to_string()).to_string());
+                    results[0] = Val::I32(0);
+                    return Ok(());
+                }
                 let resource_table = data.resource_table.clone();
-                let result = caller.data().do_host_call(id, bd, ns, op, &vec, resource_table).await;
+                let start = Instant::now();
+                let span = tracing::info_span!(
+                    "host_call",
+                    binding = bd,
+                    namespace = ns,
+                    operation = op,
+                );
+                let result = caller
+                    .data()
+                    .do_host_call(id, bd, ns, op, &vec, resource_table)
+                    .instrument(span)
+                    .await;
+                let elapsed = start.elapsed();
+                stats::record_host_call(bd, ns, op, elapsed, result.is_ok());
+                tracing::debug!(
+                    request_id = %data.request_id,
+                    binding = bd,
+                    namespace = ns,
+                    operation = op,
+                    duration_us = elapsed.as_micros() as u64,
+                    ok = result.is_ok(),
+                    "host call"
+                );
                 // let host = host.lock().unwrap();
                 let result: Result<i32, Box<dyn std::error::Error>> = Ok(match result {
                     Ok(v) => {
@@ -144,15 +196,17 @@ pub(crate) fn host_call_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn host_response_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32], vec![]);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_RESPONSE_FN,
         callback_type,
         |mut caller, params: &[Val], _results: &mut [Val]| {
             Box::new(async move {
@@ -165,16 +219,18 @@ pub(crate) fn host_response_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn host_response_len_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![], vec![ValType::I32]);
 
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_RESPONSE_LEN_FN,
         callback_type,
         |caller, _params: &[Val], results: &mut [Val]| {
             Box::new(async move {
@@ -186,15 +242,17 @@ pub(crate) fn host_response_len_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn guest_response_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32, ValType::I32], vec![]);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::GUEST_RESPONSE_FN,
         callback_type,
         |mut caller, params: &[Val], _results: &mut [Val]| {
             Box::new(async move {
@@ -208,15 +266,17 @@ pub(crate) fn guest_response_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn guest_error_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32, ValType::I32], vec![]);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::GUEST_ERROR_FN,
         callback_type,
         |mut caller, params: &[Val], _results: &mut [Val]| {
             Box::new(async move {
@@ -231,15 +291,17 @@ pub(crate) fn guest_error_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn host_error_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![ValType::I32], vec![]);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_ERROR_FN,
         callback_type,
         |mut caller, params: &[Val], _results: &mut [Val]| {
             Box::new(async move {
@@ -252,15 +314,17 @@ pub(crate) fn host_error_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 pub(crate) fn host_error_len_func(
-    store: &mut wasmtime::Store<EnvironmentState>
-) -> Func {
+    linker: &mut Linker<EnvironmentState>
+) -> Result<()> {
     let callback_type = FuncType::new(vec![], vec![ValType::I32]);
-    Func::new_async(
-        store,
+    linker.func_new_async(
+        HOST_NAMESPACE,
+        abi::HOST_ERROR_LEN_FN,
         callback_type,
         |caller, _params: &[Val], results: &mut [Val]| {
             Box::new(async move {
@@ -272,7 +336,8 @@ pub(crate) fn host_error_len_func(
                 Ok(())
             })
         },
-    )
+    )?;
+    Ok(())
 }
 
 fn get_caller_memory<T>(caller: &mut Caller<T>) -> Memory {
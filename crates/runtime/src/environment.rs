@@ -1,22 +1,30 @@
 use crate::common::{Invocation, abi};
-use crate::errors::{Error, Result, self};
+use crate::errors::{Error, Result, TrapInfo, self};
 use crate::environment_state::EnvironmentState;
+use crate::host_binding::HostBindings;
+use crate::limits::{ResourceLimits, StoreLimiter};
+use crate::logging::GuestLog;
+use crate::permissions::Permissions;
+use crate::stats;
+use crate::stdio::{GuestOutput, StdioCapture, StdioPipes};
+use crate::ticker::EpochTicker;
 use crate::wasi::{self, WasiParams};
 use crate::{callbacks};
 use parking_lot::RwLock;
 use std::sync::{Arc};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
 use wasmtime::{
-    AsContextMut, Engine, Extern, ExternType, Instance, Linker, Module, Store, TypedFunc,
+    AsContextMut, Engine, ExternType, Instance, InstancePre, Linker, Module, Store, Trap,
+    TypedFunc,
 };
 use wasmtime_wasi::WasiCtx;
 use wkr_common::resources::ResourceTable;
 
 /// The host module name / namespace that guest modules must use for imports
 pub const HOST_NAMESPACE: &str = "wapc";
-// namespace needed for some language support
-const WASI_UNSTABLE_NAMESPACE: &str = "wasi_unstable";
-const WASI_SNAPSHOT_PREVIEW1_NAMESPACE: &str = "wasi_snapshot_preview1";
+/// Amount of fuel the guest burns between two yields to the async executor
+const FUEL_SLICE: u64 = 10000;
 
 struct EngineInner {
     instance: Arc<RwLock<Instance>>,
@@ -39,110 +47,263 @@ pub struct EpochDeadlines {
     pub wapc_func: u64,
 }
 
+/// Fuel budgets enforced on waPC modules, see [`wasmtime::Config::consume_fuel`]
+///
+/// Like [`EpochDeadlines`], there is a budget for the waPC initialization code and one for
+/// each call of a user function. A guest exceeding its budget fails with
+/// [`Error::FuelExhausted`].
+#[derive(Clone, Copy, Debug)]
+pub struct FuelBudgets {
+    /// Fuel available to the waPC initialization code
+    pub wapc_init: u64,
+
+    /// Fuel available to each call of a user-defined waPC function
+    pub wapc_func: u64,
+}
+
+/// The outcome of a successful [`Environment::invoke`]
+#[derive(Clone, Debug)]
+pub struct InvocationResult {
+    /// The response of the guest
+    pub response: Vec<u8>,
+    /// Fuel burnt by the guest while handling the call
+    pub fuel_consumed: u64,
+    /// Highest amount of linear memory, in bytes, allocated while handling the call
+    pub peak_memory: usize,
+    /// What the guest wrote to its stdout and stderr, when they are captured
+    pub output: GuestOutput,
+    /// The records the guest logged through `__console_log`
+    pub logs: Vec<GuestLog>,
+}
+
 /// A waPC engine provider that encapsulates the Wasmtime WebAssembly runtime
+///
+/// The module is linked once, when the environment is created, into an
+/// [`InstancePre`]: creating a new instance of it, e.g. with [`Environment::try_clone`],
+/// doesn't resolve its imports again.
 #[allow(missing_debug_implementations)]
 pub struct Environment {
-    module: Module,
+    instance_pre: InstancePre<EnvironmentState>,
     wasi_params: WasiParams,
     inner: Option<EngineInner>,
     pub store: Store<EnvironmentState>,
     engine: Engine,
     linker: Linker<EnvironmentState>,
+    allow_unknown_imports: bool,
+    host_bindings: Arc<HostBindings>,
+    function_id: String,
+    function_version: String,
+    permissions: Option<Arc<Permissions>>,
+    limits: ResourceLimits,
+    stdio_capture: Option<StdioCapture>,
+    stdin: Vec<u8>,
     pub epoch_deadlines: Option<EpochDeadlines>,
+    epoch_ticker: Option<Arc<EpochTicker>>,
+    pub fuel_budgets: Option<FuelBudgets>,
 }
 
-impl Clone for Environment {
-    fn clone(&self) -> Self {
+impl Environment {
+    /// A new instance of the module, ready to be called: it shares the pre-linked module
+    /// of the environment, gets a store of its own, and is instantiated and initialized.
+    ///
+    /// Fails when the directories exposed to the guest can't be opened anymore, or when the
+    /// initialization code of the module fails.
+    pub async fn try_clone(&self) -> Result<Self> {
+        let mut environment = self.clone_uninitialized()?;
+        environment.init().await?;
+        Ok(environment)
+    }
+
+    /// A copy of the environment with a store of its own, not instantiated yet: the cheap
+    /// part of [`Environment::try_clone`], the module being instantiated and initialized by
+    /// [`Environment::init`] or on the first call
+    pub fn clone_uninitialized(&self) -> Result<Self> {
         let engine = self.engine.clone();
-        let wasi_ctx = init_wasi(&self.wasi_params).unwrap();
-        let resource_table = Arc::new(Mutex::new(ResourceTable::default()));
-        let store = Store::new(
-            &engine,
-            EnvironmentState {
-                wasi_ctx,
-                id: 0,
-                guest_request: Arc::new(RwLock::new(None)),
-                guest_response: Arc::new(RwLock::new(None)),
-                host_response: Arc::new(RwLock::new(None)),
-                guest_error: Arc::new(RwLock::new(None)),
-                host_error: Arc::new(RwLock::new(None)),
-                resource_table,
-            },
-        );
+        let wasi_ctx = init_wasi(&self.wasi_params)?;
+        let mut state = EnvironmentState::new(wasi_ctx, self.host_bindings.clone());
+        state.function_id = self.function_id.clone();
+        state.function_version = self.function_version.clone();
+        state.permissions = self.permissions.clone();
+        state.limiter = StoreLimiter::new(self.limits);
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limiter);
+        store.out_of_fuel_async_yield(u64::MAX, FUEL_SLICE);
 
-        match &self.inner {
-            Some(_state) => {
-                let new = Self {
-                    module: self.module.clone(),
-                    inner: None,
-                    store,
-                    engine,
-                    epoch_deadlines: self.epoch_deadlines,
-                    linker: self.linker.clone(),
-                    wasi_params: self.wasi_params.clone(),
-                };
-                // new.init();
-                new
-            }
-            None => Self {
-                module: self.module.clone(),
-                inner: None,
-                store,
-                engine,
-                epoch_deadlines: self.epoch_deadlines,
-                linker: self.linker.clone(),
-                wasi_params: self.wasi_params.clone(),
-            },
-        }
+        Ok(Self {
+            instance_pre: self.instance_pre.clone(),
+            inner: None,
+            store,
+            engine,
+            epoch_deadlines: self.epoch_deadlines,
+            epoch_ticker: self.epoch_ticker.clone(),
+            fuel_budgets: self.fuel_budgets,
+            linker: self.linker.clone(),
+            allow_unknown_imports: self.allow_unknown_imports,
+            host_bindings: self.host_bindings.clone(),
+            function_id: self.function_id.clone(),
+            function_version: self.function_version.clone(),
+            permissions: self.permissions.clone(),
+            limits: self.limits,
+            stdio_capture: self.stdio_capture,
+            stdin: Vec::new(),
+            wasi_params: self.wasi_params.clone(),
+        })
     }
-}
 
-impl Environment {
-    pub fn new_with_engine(buf: &[u8], engine: Engine, wasi: Option<WasiParams>) -> Result<Self> {
+    pub fn new_with_engine(
+        buf: &[u8],
+        engine: Engine,
+        wasi: Option<WasiParams>,
+        host_bindings: HostBindings,
+    ) -> Result<Self> {
         let module = Module::new(&engine, buf)?;
+        Self::new_with_module(module, engine, wasi, host_bindings, false)
+    }
 
-        let mut linker: Linker<EnvironmentState> = Linker::new(&engine);
-        wasmtime_wasi::tokio::add_to_linker(&mut linker, |s| &mut s.wasi_ctx).unwrap();
+    /// Creates an environment from a module already compiled by `engine`, e.g. one loaded
+    /// from a [`ModuleCache`](crate::module_cache::ModuleCache)
+    ///
+    /// Imports the host doesn't provide are reported with [`Error::UnsupportedImport`],
+    /// unless `allow_unknown_imports` is set: unknown functions are then linked to stubs
+    /// that trap when called.
+    pub fn new_with_module(
+        module: Module,
+        engine: Engine,
+        wasi: Option<WasiParams>,
+        host_bindings: HostBindings,
+        allow_unknown_imports: bool,
+    ) -> Result<Self> {
         let wasi_params = wasi.unwrap_or_default();
-        let wasi_ctx = wasi::init_ctx(
-            &wasi::compute_preopen_dirs(&wasi_params.preopened_dirs, &wasi_params.map_dirs)
-                .unwrap(),
-            &wasi_params.argv,
-            &wasi_params.env_vars,
-        )
-        .unwrap();
-        let resource_table = Arc::new(Mutex::new(ResourceTable::default()));
+        let wasi_ctx = init_wasi(&wasi_params)?;
+        let host_bindings = Arc::new(host_bindings);
         let mut store = Store::new(
             &engine,
-            EnvironmentState {
-                wasi_ctx,
-                id: 0,
-                guest_request: Arc::new(RwLock::new(None)),
-                guest_response: Arc::new(RwLock::new(None)),
-                host_response: Arc::new(RwLock::new(None)),
-                guest_error: Arc::new(RwLock::new(None)),
-                host_error: Arc::new(RwLock::new(None)),
-                resource_table,
-            },
+            EnvironmentState::new(wasi_ctx, host_bindings.clone()),
         );
-        store.out_of_fuel_async_yield(u64::MAX, 10000);
+        store.limiter(|state| &mut state.limiter);
+        store.out_of_fuel_async_yield(u64::MAX, FUEL_SLICE);
+
+        let mut linker = new_linker(&engine)?;
+        let instance_pre = link_module(&mut linker, &mut store, &module, allow_unknown_imports)?;
 
         Ok(Environment {
-            module,
+            instance_pre,
             // #[cfg(feature = "wasi")]
             wasi_params,
             inner: None,
             store,
             engine,
             linker,
+            allow_unknown_imports,
+            host_bindings,
+            function_id: String::new(),
+            function_version: String::new(),
+            permissions: None,
+            limits: ResourceLimits::default(),
+            stdio_capture: Some(StdioCapture::default()),
+            stdin: Vec::new(),
             epoch_deadlines: None,
+            epoch_ticker: None,
+            fuel_budgets: None,
         })
     }
 
+    /// Sets the id of the function implemented by the module, used to attribute guest logs
+    pub fn set_function_id(&mut self, function_id: String) {
+        self.store.data_mut().function_id = function_id.clone();
+        self.function_id = function_id;
+    }
+
+    /// Sets the version of the function implemented by the module, used to attribute guest logs
+    pub fn set_function_version(&mut self, function_version: String) {
+        self.store.data_mut().function_version = function_version.clone();
+        self.function_version = function_version;
+    }
+
+    /// Restricts the host calls the guest can perform, `None` lifts every restriction
+    pub fn set_permissions(&mut self, permissions: Option<Permissions>) {
+        let permissions = permissions.map(Arc::new);
+        self.store.data_mut().permissions = permissions.clone();
+        self.permissions = permissions;
+    }
+
+    /// Limits the memory, tables and instances the guest can allocate. Must be set before
+    /// the environment is initialized.
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.store.data_mut().limiter = StoreLimiter::new(limits);
+        self.limits = limits;
+    }
+
+    /// Captures the stdio of the guest in memory, the default, or inherits the host's with
+    /// `None`. The output of each invocation is returned in its [`InvocationResult`].
+    pub fn set_stdio_capture(&mut self, capture: Option<StdioCapture>) {
+        self.stdio_capture = capture;
+    }
+
+    /// The stdin of the guest for the next invocation, when its stdio is captured
+    pub fn set_stdin(&mut self, stdin: Vec<u8>) {
+        self.stdin = stdin;
+    }
+
+    /// The resources of the guest, where the host opens the ones it hands to the next
+    /// invocation. They are dropped when the environment is reset.
+    pub fn resource_table(&self) -> Arc<tokio::sync::Mutex<ResourceTable>> {
+        self.store.data().resource_table.clone()
+    }
+
+    /// Sets the id of the request being handled, used to attribute guest output and logs
+    pub fn set_request_id<T: Into<String>>(&mut self, request_id: T) {
+        self.store.data_mut().request_id = request_id.into();
+    }
+
+    /// The id of the function this module implements
+    pub fn function_id(&self) -> &str {
+        &self.function_id
+    }
+
+    /// Increments the epoch of the engine every `tick`, so the [`EpochDeadlines`] expire.
+    ///
+    /// The ticker is a task of the current Tokio runtime, shared by every environment of
+    /// the engine, and stops when the last of them is dropped. Fails when called outside
+    /// of a Tokio runtime.
+    pub fn start_epoch_ticker(&mut self, tick: Duration) -> Result<()> {
+        self.epoch_ticker = Some(EpochTicker::for_engine(&self.engine, tick)?);
+        Ok(())
+    }
+
     pub async fn init(
         &mut self,
     ) -> Result<()> {
-        let instance = instance_from_module(&mut self.store, &self.module, &self.linker).await?;
+        let pipes = self.install_stdio();
+        let span = {
+            let state = self.store.data();
+            tracing::info_span!(
+                "initialization",
+                function = %state.function_id,
+                version = %state.function_version,
+            )
+        };
+        let start = Instant::now();
+        let result = self.init_instance().instrument(span).await;
+        // the output of the initialization code isn't returned anywhere
+        self.collect_stdio(pipes);
+        if result.is_ok() {
+            stats::record_init(&self.function_id, &self.function_version, start.elapsed());
+        }
+        result
+    }
+
+    async fn init_instance(&mut self) -> Result<()> {
+        self.refuel(self.fuel_budgets.map(|budgets| budgets.wapc_init))?;
+        if let Some(deadlines) = &self.epoch_deadlines {
+            // the module's start function runs while instantiating
+            self.store.set_epoch_deadline(deadlines.wapc_init);
+        }
+        let instance = self
+            .instance_pre
+            .instantiate_async(&mut self.store)
+            .await
+            .map_err(|e| self.map_init_error(e))?;
         let instance_ref = Arc::new(RwLock::new(instance));
         let gc = guest_call_fn(self.store.as_context_mut(), &instance_ref)?;
         self.inner = Some(EngineInner {
@@ -153,6 +314,17 @@ impl Environment {
         Ok(())
     }
 
+    /// Whether the module has been instantiated in the store of this environment
+    pub fn is_initialized(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Clears what previous invocations left behind in the store: the waPC buffers and the
+    /// resources still open. The instance, and therefore its linear memory, is kept.
+    pub fn reset(&mut self) {
+        self.store.data_mut().reset();
+    }
+
     fn set_store(&mut self, inv: Invocation) {
         let store = self.store.data_mut();
 
@@ -175,52 +347,125 @@ impl Environment {
         store.guest_response.read().clone()
     }
     pub async fn call(&mut self, op: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        self.invoke(op, payload).await.map(|result| result.response)
+    }
+
+    /// Calls `op`, like [`Environment::call`], and reports the resources the guest used
+    pub async fn invoke(&mut self, op: &str, payload: &[u8]) -> Result<InvocationResult> {
+        let start = Instant::now();
+        let result = self.invoke_guest(op, payload).await;
+        stats::record_invocation(&self.function_id, &self.function_version, start.elapsed(), &result);
+        result
+    }
+
+    async fn invoke_guest(&mut self, op: &str, payload: &[u8]) -> Result<InvocationResult> {
+        if self.inner.is_none() {
+            self.init().await?;
+        }
+
         let inv = Invocation::new(op, payload.to_vec());
         let op_len = inv.operation.len();
         let msg_len = inv.msg.len();
 
         self.set_store(inv);
 
-        let callresult = match self.call_engine(op_len as i32, msg_len as i32).await {
-            Ok(c) => c,
-            Err(e) => {
-                return Err(errors::Error::GuestCallFailure(e.to_string()));
-            }
+        self.refuel(self.fuel_budgets.map(|budgets| budgets.wapc_func))?;
+        let fuel_before = self.store.fuel_consumed().unwrap_or_default();
+        // a denied growth the guest recovered from earlier must not fail this call
+        let limiter = &mut self.store.data_mut().limiter;
+        limiter.reset_peak();
+        limiter.take_exceeded();
+        let span = {
+            let state = self.store.data_mut();
+            state.id += 1;
+            state.logs.clear();
+            tracing::info_span!(
+                "invocation",
+                function = %state.function_id,
+                version = %state.function_version,
+                request_id = %state.request_id,
+                invocation = state.id,
+                operation = op,
+            )
         };
 
-        if callresult == 0 {
+        let pipes = self.install_stdio();
+        let callresult = self
+            .call_engine(op, op_len as i32, msg_len as i32)
+            .instrument(span.clone())
+            .await;
+        let output = span.in_scope(|| self.collect_stdio(pipes));
+        let logs = std::mem::take(&mut self.store.data_mut().logs);
+        let fuel_consumed = self.store.fuel_consumed().unwrap_or_default() - fuel_before;
+        let peak_memory = self.store.data().limiter.peak_memory();
+
+        // a denied growth only matters if the guest didn't recover from it
+        let exceeded = self.store.data_mut().limiter.take_exceeded();
+        let callresult = callresult?;
+
+        let response = if callresult == 0 {
             // invocation failed
-            // let lock = store.guest_error.read();
+            if let Some(exceeded) = exceeded {
+                return Err(Error::MemoryLimitExceeded(exceeded));
+            }
             let lock = self.get_guest_error();
             match lock {
-                Some(ref s) => Err(errors::Error::GuestCallFailure(s.clone())),
-                None => Err(errors::Error::GuestCallFailure(
-                    "No error message set for call failure".to_owned(),
-                )),
+                Some(ref s) => Err(errors::Error::GuestError(TrapInfo::new(s.clone()))),
+                None => Err(errors::Error::GuestError(TrapInfo::new(
+                    "No error message set for call failure",
+                ))),
             }
         } else {
             // invocation succeeded
             match self.get_guest_response() {
                 Some(ref e) => Ok(e.clone()),
                 None => {
-                    // let lock = store.guest_error.read();
                     let lock = self.get_guest_error();
                     match lock {
-                        Some(ref s) => Err(errors::Error::GuestCallFailure(s.clone())),
+                        Some(ref s) => Err(errors::Error::GuestError(TrapInfo::new(s.clone()))),
                         None => Err(errors::Error::GuestCallFailure(
                             "No error message OR response set for call success".to_owned(),
                         )),
                     }
                 }
             }
+        }?;
+
+        Ok(InvocationResult {
+            response,
+            fuel_consumed,
+            peak_memory,
+            output,
+            logs,
+        })
+    }
+
+    /// Replaces the stdio of the guest with in-memory pipes, if it is captured
+    fn install_stdio(&mut self) -> Option<StdioPipes> {
+        let capture = self.stdio_capture?;
+        let stdin = std::mem::take(&mut self.stdin);
+        Some(StdioPipes::install(
+            &mut self.store.data_mut().wasi_ctx,
+            capture.limit,
+            stdin,
+        ))
+    }
+
+    /// Takes back what the guest wrote to the pipes, and forwards it if configured to
+    fn collect_stdio(&mut self, pipes: Option<StdioPipes>) -> GuestOutput {
+        let (pipes, capture) = match (pipes, self.stdio_capture) {
+            (Some(pipes), Some(capture)) => (pipes, capture),
+            _ => return GuestOutput::default(),
+        };
+        let state = self.store.data_mut();
+        let output = pipes.collect(&mut state.wasi_ctx);
+        if capture.forward {
+            output.forward(&state.function_id, &state.request_id);
         }
+        output
     }
 
-    async fn call_engine(
-        &mut self,
-        op_length: i32,
-        msg_length: i32,
-    ) -> std::result::Result<i32, Box<(dyn std::error::Error + Send + Sync + 'static)>> {
+    async fn call_engine(&mut self, op: &str, op_length: i32, msg_length: i32) -> Result<i32> {
         if let Some(deadlines) = &self.epoch_deadlines {
             // the deadline counter must be set before invoking the wasm function
             self.store.set_epoch_deadline(deadlines.wapc_func);
@@ -236,20 +481,64 @@ impl Environment {
             Ok(result) => Ok(result),
             Err(trap) => {
                 error!("Failure invoking guest module handler: {:?}", trap);
-                let guest_error = trap.to_string();
-                // if let Some(trap_code) = trap.trap_code() {
-                //   if matches!(trap_code, wasmtime::TrapCode::Interrupt) {
-                //     guest_error = "guest code interrupted, execution deadline exceeded".to_owned();
-                //   }
-                // }
-                // let mut host = engine_inner.host.lock().unwrap();
-                let store = self.store.data();
-                store.set_guest_error(guest_error);
-                Ok(0)
+                Err(self.map_call_error(op, trap))
             }
         }
     }
 
+    /// Leaves exactly `budget` fuel to the guest, or unlimited fuel when there is no budget.
+    /// The fuel is handed out in slices so the guest keeps yielding to the executor.
+    fn refuel(&mut self, budget: Option<u64>) -> Result<()> {
+        let (fuel, injections) = match budget {
+            Some(budget) => (budget % FUEL_SLICE, budget / FUEL_SLICE),
+            None => (FUEL_SLICE, u64::MAX),
+        };
+        let remaining = self.store.consume_fuel(0)?;
+        if remaining > fuel {
+            self.store.consume_fuel(remaining - fuel)?;
+        } else {
+            self.store.add_fuel(fuel - remaining)?;
+        }
+        self.store.out_of_fuel_async_yield(injections, FUEL_SLICE);
+        Ok(())
+    }
+
+    /// Maps a failure of a guest call to the error reported to the embedder
+    fn map_call_error(&mut self, op: &str, error: anyhow::Error) -> Error {
+        if let Some(exceeded) = self.store.data_mut().limiter.take_exceeded() {
+            return Error::MemoryLimitExceeded(exceeded);
+        }
+        let trap = TrapInfo::from_error(&error);
+        match trap.code {
+            Some(Trap::Interrupt) => Error::GuestCallTimeout {
+                operation: op.to_owned(),
+                trap,
+            },
+            Some(Trap::OutOfFuel) => Error::FuelExhausted {
+                budget: self.fuel_budgets.map(|budgets| budgets.wapc_func).unwrap_or(u64::MAX),
+                trap,
+            },
+            _ => Error::from_trap(trap),
+        }
+    }
+
+    /// Maps a failure of the initialization code to the error reported to the embedder
+    fn map_init_error(&mut self, error: anyhow::Error) -> Error {
+        if let Some(exceeded) = self.store.data_mut().limiter.take_exceeded() {
+            return Error::MemoryLimitExceeded(exceeded);
+        }
+        let trap = TrapInfo::from_error(&error);
+        match trap.code {
+            Some(Trap::Interrupt) => Error::InitializationFailedTimeout(trap),
+            Some(Trap::OutOfFuel) => Error::FuelExhausted {
+                budget: self.fuel_budgets.map(|budgets| budgets.wapc_init).unwrap_or(u64::MAX),
+                trap,
+            },
+            Some(_) => Error::from_trap(trap),
+            None => Error::InitializationFailed(error.into()),
+        }
+    }
+
     async fn replace(
         &mut self,
         module: &[u8],
@@ -259,8 +548,20 @@ impl Environment {
             module.len()
         );
 
-        let new_instance =
-            instance_from_buffer(&mut self.store, &self.engine, module, &self.linker).await?;
+        let module = Module::new(&self.engine, module)?;
+        let instance_pre = link_module(
+            &mut self.linker,
+            &mut self.store,
+            &module,
+            self.allow_unknown_imports,
+        )?;
+        self.instance_pre = instance_pre;
+        if self.inner.is_none() {
+            return Ok(self.init().await?);
+        }
+
+        self.refuel(self.fuel_budgets.map(|budgets| budgets.wapc_init))?;
+        let new_instance = self.instance_pre.instantiate_async(&mut self.store).await?;
         *self.inner.as_ref().unwrap().instance.write() = new_instance;
 
         Ok(self.initialize().await?)
@@ -287,107 +588,64 @@ impl Environment {
                     .instance
                     .read()
                     .get_typed_func(&mut self.store, starter)?;
-                starter_func
-                    .call_async(&mut self.store, ())
-                    .await
-                    .map_err(|trap| {
-                        Error::InitializationFailed(trap.into())
-                        // if let Some(trap_code) = trap.trap_code() {
-                        //   if matches!(trap_code, wasmtime::TrapCode::Interrupt) {
-                        //     Error::InitializationFailedTimeout((*starter).to_owned())
-                        //   } else {
-                        //     Error::InitializationFailed(trap.into())
-                        //   }
-                        // } else {
-                        //   Error::InitializationFailed(trap.into())
-                        // }
-                    })?;
+                let result = starter_func.call_async(&mut self.store, ()).await;
+                result.map_err(|trap| self.map_init_error(trap))?;
             }
         }
         Ok(())
     }
 }
 
-async fn instance_from_buffer(
-    store: &mut Store<EnvironmentState>,
-    engine: &Engine,
-    buf: &[u8],
-    linker: &Linker<EnvironmentState>,
-) -> Result<Instance> {
-    let module = Module::new(engine, buf).unwrap();
-    let imports = arrange_imports(&module, store, linker);
-    Ok(
-        wasmtime::Instance::new_async(store.as_context_mut(), &module, imports?.as_slice())
-            .await
-            .unwrap(),
-    )
+/// A linker providing the WASI and waPC host functions
+fn new_linker(engine: &Engine) -> Result<Linker<EnvironmentState>> {
+    let mut linker: Linker<EnvironmentState> = Linker::new(engine);
+    wasmtime_wasi::tokio::add_to_linker(&mut linker, |s| &mut s.wasi_ctx)?;
+    callbacks::add_to_linker(&mut linker)?;
+    Ok(linker)
 }
 
-async fn instance_from_module(
+/// Checks that `linker` provides every import of `module` before pre-instantiating it.
+/// With `allow_unknown_imports`, the missing functions are defined as trapping stubs.
+fn link_module(
+    linker: &mut Linker<EnvironmentState>,
     store: &mut Store<EnvironmentState>,
     module: &Module,
-    linker: &Linker<EnvironmentState>,
-) -> Result<Instance> {
-    let imports = arrange_imports(module, store, linker);
-    Ok(
-        wasmtime::Instance::new_async(store.as_context_mut(), module, imports?.as_slice())
-            .await
-            .unwrap(),
-    )
-}
-
-// #[cfg(feature = "wasi")]
-fn init_wasi(params: &WasiParams) -> Result<WasiCtx> {
-    wasi::init_ctx(
-        &wasi::compute_preopen_dirs(&params.preopened_dirs, &params.map_dirs).unwrap(),
-        &params.argv,
-        &params.env_vars,
-    )
-    .map_err(|e| Error::InitializationFailed(e))
-}
+    allow_unknown_imports: bool,
+) -> Result<InstancePre<EnvironmentState>> {
+    for import in module.imports() {
+        if linker.get(&mut *store, import.module(), import.name()).is_some() {
+            continue;
+        }
 
-/// wasmtime requires that the list of callbacks be "zippable" with the list
-/// of module imports. In order to ensure that both lists are in the same
-/// order, we have to loop through the module imports and instantiate the
-/// corresponding callback. We **cannot** rely on a predictable import order
-/// in the wasm module
-#[allow(clippy::unnecessary_wraps)]
-fn arrange_imports(
-    module: &Module,
-    store: &mut Store<EnvironmentState>,
-    linker: &Linker<EnvironmentState>,
-) -> Result<Vec<Extern>> {
-    Ok(module
-        .imports()
-        .filter_map(|imp| {
-            if let ExternType::Func(_) = imp.ty() {
-                match imp.module() {
-                    HOST_NAMESPACE => Some(callback_for_import(store, imp.name())),
-                    WASI_SNAPSHOT_PREVIEW1_NAMESPACE | WASI_UNSTABLE_NAMESPACE => {
-                        linker.get_by_import(store.as_context_mut(), &imp)
+        let unsupported = Error::UnsupportedImport {
+            module: import.module().to_owned(),
+            name: import.name().to_owned(),
+        };
+        match import.ty() {
+            ExternType::Func(ty) if allow_unknown_imports => {
+                warn!("{}, linking a stub that traps when called", unsupported);
+                let (module, name) = (import.module().to_owned(), import.name().to_owned());
+                linker.func_new(import.module(), import.name(), ty, move |_, _, _| {
+                    Err(Error::UnsupportedImport {
+                        module: module.clone(),
+                        name: name.clone(),
                     }
-                    other => panic!("import module `{}` was not found", other), //TODO: get rid of panic
-                }
-            } else {
-                None
+                    .into())
+                })?;
             }
-        })
-        .collect())
+            _ => return Err(unsupported),
+        }
+    }
+
+    Ok(linker.instantiate_pre(module)?)
 }
 
-fn callback_for_import(store: &mut Store<EnvironmentState>, import: &str) -> Extern {
-    match import {
-        abi::HOST_CONSOLE_LOG => callbacks::console_log_func(store).into(),
-        abi::HOST_CALL => callbacks::host_call_func(store).into(),
-        abi::GUEST_REQUEST_FN => callbacks::guest_request_func(store).into(),
-        abi::HOST_RESPONSE_FN => callbacks::host_response_func(store).into(),
-        abi::HOST_RESPONSE_LEN_FN => callbacks::host_response_len_func(store).into(),
-        abi::GUEST_RESPONSE_FN => callbacks::guest_response_func(store).into(),
-        abi::GUEST_ERROR_FN => callbacks::guest_error_func(store).into(),
-        abi::HOST_ERROR_FN => callbacks::host_error_func(store).into(),
-        abi::HOST_ERROR_LEN_FN => callbacks::host_error_len_func(store).into(),
-        _ => unreachable!(),
-    }
+// #[cfg(feature = "wasi")]
+fn init_wasi(params: &WasiParams) -> Result<WasiCtx> {
+    let preopen_dirs = wasi::compute_preopen_dirs(&params.preopened_dirs, &params.map_dirs)
+        .map_err(Error::InitializationFailed)?;
+    wasi::init_ctx(&preopen_dirs, &params.argv, &params.env_vars)
+        .map_err(Error::InitializationFailed)
 }
 
 // Called once, then the result is cached. This returns a `Func` that corresponds
@@ -401,3 +659,132 @@ fn guest_call_fn(
         .get_typed_func::<(i32, i32), i32>(store, abi::GUEST_CALL)
         .map_err(|_| Error::GuestCallNotFound)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::EnvironmentBuilder;
+
+    /// A waPC guest whose `__guest_call` runs `body` then responds `ok`. `fields` are
+    /// added to the module, before its memory.
+    fn guest(fields: &str, body: &str) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(module
+                (import "wapc" "__guest_response" (func $response (param i32 i32)))
+                {}
+                (memory (export "memory") 1)
+                (data (i32.const 0) "ok")
+                (func (export "__guest_call") (param i32 i32) (result i32)
+                    {}
+                    (call $response (i32.const 0) (i32.const 2))
+                    (i32.const 1))
+            )"#,
+            fields, body
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn fuel_exhausted() {
+        let module = guest("", "(loop $spin (br $spin))");
+        let mut environment = EnvironmentBuilder::new(&module)
+            .fuel_budgets(1_000_000, 10_000)
+            .build()
+            .unwrap();
+
+        let error = environment.call("spin", b"").await.unwrap_err();
+        assert!(matches!(error, Error::FuelExhausted { budget: 10_000, .. }), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn epoch_deadline_exceeded() {
+        let module = guest("", "(loop $spin (br $spin))");
+        let mut environment = EnvironmentBuilder::new(&module)
+            .enable_epoch_interruptions(10, 10)
+            .epoch_tick(Duration::from_millis(1))
+            .build()
+            .unwrap();
+
+        let error = environment.call("spin", b"").await.unwrap_err();
+        assert!(matches!(error, Error::GuestCallTimeout { .. }), "{}", error);
+    }
+
+    #[test]
+    fn epoch_tick_needs_a_runtime() {
+        let module = guest("", "");
+        let result = EnvironmentBuilder::new(&module)
+            .enable_epoch_interruptions(10, 10)
+            .epoch_tick(Duration::from_millis(1))
+            .build();
+        assert!(matches!(result, Err(Error::General(_))));
+    }
+
+    #[tokio::test]
+    async fn memory_limit_exceeded() {
+        let grow = "(if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+                        (then (return (i32.const 0))))";
+        let module = guest("", grow);
+        let mut environment = EnvironmentBuilder::new(&module)
+            .memory_limit(64 * 1024)
+            .build()
+            .unwrap();
+
+        let error = environment.call("grow", b"").await.unwrap_err();
+        assert!(matches!(error, Error::MemoryLimitExceeded(_)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn recovered_memory_growth() {
+        // the initialization recovers from a denied growth, the call then fails on its own
+        let init = r#"(func (export "wapc_init") (drop (memory.grow (i32.const 1))))"#;
+        let module = guest(init, "(return (i32.const 0))");
+        let mut environment = EnvironmentBuilder::new(&module)
+            .memory_limit(64 * 1024)
+            .build()
+            .unwrap();
+
+        let error = environment.call("fail", b"").await.unwrap_err();
+        assert!(matches!(error, Error::GuestError(_)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn unsupported_imports() {
+        let module = guest(r#"(import "env" "missing" (func $missing))"#, "(call $missing)");
+        let error = EnvironmentBuilder::new(&module).build().err().unwrap();
+        assert!(matches!(error, Error::UnsupportedImport { .. }), "{}", error);
+
+        let mut environment = EnvironmentBuilder::new(&module)
+            .allow_unknown_imports(true)
+            .build()
+            .unwrap();
+        let error = environment.call("missing", b"").await.unwrap_err();
+        assert!(error.to_string().contains("env::missing"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn clones_are_ready_to_call() {
+        let module = guest("", "");
+        let environment = EnvironmentBuilder::new(&module).build().unwrap();
+
+        let mut clone = environment.try_clone().await.unwrap();
+        assert!(clone.is_initialized());
+        assert_eq!(clone.call("hello", b"").await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn captures_stdout() {
+        let fd_write = r#"(import "wasi_snapshot_preview1" "fd_write"
+                            (func $fd_write (param i32 i32 i32 i32) (result i32)))"#;
+        // writes the `ok` of the response to stdout, through an iovec at offset 16
+        let print = "(i32.store (i32.const 16) (i32.const 0))
+                     (i32.store (i32.const 20) (i32.const 2))
+                     (drop (call $fd_write (i32.const 1) (i32.const 16) (i32.const 1) (i32.const 24)))";
+        let module = guest(fd_write, print);
+        let mut environment = EnvironmentBuilder::new(&module).build().unwrap();
+
+        let result = environment.invoke("print", b"").await.unwrap();
+        assert_eq!(result.response, b"ok");
+        assert_eq!(result.output.stdout, b"ok");
+        assert!(result.output.stderr.is_empty());
+    }
+}
@@ -2,10 +2,13 @@ use std::{sync::{Arc}};
 use parking_lot::RwLock;
 use wkr_common::resources::ResourceTable;
 use wasmtime_wasi::WasiCtx;
-use wkr_fetch::process_ops;
-use wkr_database::process_database_ops;
 use tokio::sync::Mutex;
 use crate::common::Invocation;
+use crate::errors::Error;
+use crate::host_binding::{HostBindings, HostResult};
+use crate::limits::StoreLimiter;
+use crate::logging::{GuestLog, MAX_LOGS_PER_INVOCATION};
+use crate::permissions::Permissions;
 
 /// Module state is essentially a 'handle' that is passed to a runtime engine to allow it
 /// to read and write relevant data as different low-level functions are executed during
@@ -19,11 +22,57 @@ pub struct EnvironmentState {
   pub guest_error: Arc<RwLock<Option<String>>>,
   pub host_error: Arc<RwLock<Option<String>>>,
   // pub host_callback: Option<Box<HostCallback>>,
+  /// Sequence number of the current invocation in this store
   pub id: u64,
   pub resource_table: Arc<Mutex<ResourceTable>>,
+  pub host_bindings: Arc<HostBindings>,
+  /// The id of the function this module implements, used to attribute logs
+  pub function_id: String,
+  /// The version of the function this module implements
+  pub function_version: String,
+  /// The id of the request being handled, used to attribute logs
+  pub request_id: String,
+  /// The records logged by the guest during the current invocation
+  pub logs: Vec<GuestLog>,
+  /// The host calls the guest is allowed to perform, unrestricted when `None`
+  pub permissions: Option<Arc<Permissions>>,
+  /// Enforces the resource limits of the store and tracks its memory
+  pub limiter: StoreLimiter,
 }
 
 impl EnvironmentState {
+    /// Creates the state of a fresh store, with empty waPC buffers and resource table
+    pub fn new(wasi_ctx: WasiCtx, host_bindings: Arc<HostBindings>) -> Self {
+      EnvironmentState {
+        wasi_ctx,
+        id: 0,
+        guest_request: Arc::new(RwLock::new(None)),
+        guest_response: Arc::new(RwLock::new(None)),
+        host_response: Arc::new(RwLock::new(None)),
+        guest_error: Arc::new(RwLock::new(None)),
+        host_error: Arc::new(RwLock::new(None)),
+        resource_table: Arc::new(Mutex::new(ResourceTable::default())),
+        host_bindings,
+        function_id: String::new(),
+        function_version: String::new(),
+        request_id: String::new(),
+        logs: Vec::new(),
+        permissions: None,
+        limiter: StoreLimiter::default(),
+      }
+    }
+
+    /// Drops the waPC buffers and every resource opened by the guest
+    pub fn reset(&mut self) {
+      *self.guest_request.write() = None;
+      *self.guest_response.write() = None;
+      *self.host_response.write() = None;
+      *self.guest_error.write() = None;
+      *self.host_error.write() = None;
+      self.logs.clear();
+      self.resource_table = Arc::new(Mutex::new(ResourceTable::default()));
+    }
+
     /// Retrieves the value, if any, of the current guest request
     pub fn get_guest_request(&self) -> Option<Invocation> {
       self.guest_request.read().clone()
@@ -66,10 +115,35 @@ impl EnvironmentState {
     pub fn set_host_error(&self, error: String) {
       *self.host_error.write() = Some(error);
     }
-      /// Invoked when the guest module wants to write a message to the host's `stdout`
-  pub fn do_console_log(&self, msg: &str) {
-    info!("Guest module {}: {}", self.id, msg);
+  /// Invoked when the guest module logs a message, plain or structured (see [`GuestLog`])
+  pub fn do_console_log(&mut self, msg: &str) {
+    let log = GuestLog::parse(msg);
+    log.emit();
+    if self.logs.len() < MAX_LOGS_PER_INVOCATION {
+      self.logs.push(log);
+    }
+  }
+  /// Checks the guest's permissions before a `__host_call` is dispatched
+  pub fn check_host_call(
+    &self,
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    payload: &[u8],
+  ) -> Result<(), Error> {
+    match &self.permissions {
+      Some(permissions) => {
+        let scope = self
+          .host_bindings
+          .get(binding, namespace, operation)
+          .and_then(|handler| handler.scope(namespace, operation, payload));
+        permissions.check(binding, namespace, operation, scope.as_deref())
+      }
+      None => Ok(()),
+    }
   }
+
+  /// Dispatches a `__host_call` to the host binding registered for it
   pub async fn do_host_call(
     &self,
     id: u64,
@@ -78,26 +152,19 @@ impl EnvironmentState {
     operation: &str,
     payload: &[u8],
     resource_table: Arc<Mutex<ResourceTable>>,
-  // ) -> Result<Vec<u8>> {
-  ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    
-    match (binding, namespace, operation) {
-        ("fetch", _, _) => {
-          println!("fetch>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>");
-            let result = process_ops(id, binding, namespace, operation, payload, resource_table).await;
-
-            return result;
-        },
-        ("database", _, _) =>{
-          println!("database>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>");
-          let result = process_database_ops(id, binding, namespace, operation, payload, resource_table).await;
-
-            return result;
-        }
-        _ => {}
+  ) -> HostResult {
+    match self.host_bindings.get(binding, namespace, operation) {
+      Some(handler) => {
+        handler
+          .call(id, binding, namespace, operation, payload, resource_table)
+          .await
+      }
+      None => Err(Box::new(Error::HostBindingNotFound(
+        binding.to_owned(),
+        namespace.to_owned(),
+        operation.to_owned(),
+      ))),
     }
-
-      Ok(vec![])
   }
 }
 
@@ -111,6 +178,13 @@ impl std::fmt::Debug for EnvironmentState {
       .field("host_error", &self.host_error)
       // .field("host_callback", &self.host_callback.as_ref().map(|_| Some("Some(Fn)")))
       .field("id", &self.id)
+      .field("host_bindings", &self.host_bindings)
+      .field("function_id", &self.function_id)
+      .field("function_version", &self.function_version)
+      .field("request_id", &self.request_id)
+      .field("logs", &self.logs.len())
+      .field("permissions", &self.permissions)
+      .field("limiter", &self.limiter)
       .finish()
   }
 }
\ No newline at end of file
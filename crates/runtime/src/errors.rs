@@ -1,3 +1,5 @@
+use wasmtime::{FrameInfo, Trap, WasmBacktrace};
+
 /// A convenience wrapper of `Result` that relies on
 /// [`wasmtime_provider::errors::Error`](crate::errors::Error)
 /// to hold errors
@@ -18,6 +20,32 @@ pub enum Error {
     /// Error during a host call.
     #[error("Error during host call: {0}")]
     HostCallFailure(Box<dyn std::error::Error + Sync + Send>),
+    /// The guest invoked a host binding that has not been registered.
+    #[error("No host binding registered for {0}:{1}:{2}")]
+    HostBindingNotFound(String, String, String),
+    /// The buffer is not a valid WebAssembly module.
+    #[error("Invalid module: {0}")]
+    InvalidModule(String),
+    /// The module imports something the host doesn't provide.
+    #[error("Unsupported import: `{module}::{name}` is not provided by the host")]
+    UnsupportedImport {
+        /// The namespace of the import
+        module: String,
+        /// The name of the import
+        name: String,
+    },
+    /// The guest is not allowed to perform a host call.
+    #[error("Permission denied for {binding}:{namespace}:{operation}: {reason}")]
+    PermissionDenied {
+        /// The binding of the denied host call
+        binding: String,
+        /// The namespace of the denied host call
+        namespace: String,
+        /// The operation of the denied host call
+        operation: String,
+        /// Why the call was denied
+        reason: String,
+    },
     /// Initialization Failed.
     #[error("Initialization failed: {0}")]
     InitFailed(String),
@@ -38,9 +66,55 @@ pub enum Error {
     #[error("Initialization failed: {0}")]
     InitializationFailed(Box<dyn std::error::Error + Send + Sync>),
 
-    /// Wasmtime initialization failed
-    #[error("Initialization failed: {0} init interrupted, execution deadline exceeded")]
-    InitializationFailedTimeout(String),
+    /// The initialization of the guest was interrupted, its epoch deadline expired
+    #[error("Initialization failed: init interrupted, execution deadline exceeded: {0}")]
+    InitializationFailedTimeout(TrapInfo),
+
+    /// A guest call was interrupted, its epoch deadline expired
+    #[error("Guest call failure: {operation} interrupted, execution deadline exceeded")]
+    GuestCallTimeout {
+        /// The operation being called
+        operation: String,
+        /// Where the guest was interrupted
+        trap: TrapInfo,
+    },
+
+    /// The guest tried to allocate more memory, or table elements, than it is allowed to
+    #[error("Memory limit exceeded: {0}")]
+    MemoryLimitExceeded(String),
+
+    /// The guest used up its fuel budget
+    #[error("Fuel budget of {budget} exhausted")]
+    FuelExhausted {
+        /// The budget of the call, or of the initialization
+        budget: u64,
+        /// Where the guest ran out of fuel
+        trap: TrapInfo,
+    },
+
+    /// The guest executed an `unreachable` instruction, e.g. when aborting
+    #[error("Guest trapped: {0}")]
+    Unreachable(TrapInfo),
+
+    /// The guest accessed its linear memory out of bounds
+    #[error("Guest trapped: {0}")]
+    MemoryOutOfBounds(TrapInfo),
+
+    /// The guest exhausted its call stack
+    #[error("Guest trapped: {0}")]
+    StackOverflow(TrapInfo),
+
+    /// Any other trap raised by the guest, see the trap code
+    #[error("Guest trapped: {0}")]
+    GuestTrap(TrapInfo),
+
+    /// A host function called by the guest failed
+    #[error("Host function failure: {0}")]
+    HostError(TrapInfo),
+
+    /// The guest reported an error through `__guest_error`
+    #[error("Guest error: {0}")]
+    GuestError(TrapInfo),
 
     /// The guest call function was not exported by the guest.
     #[error("Guest call function (__guest_call) not exported by wasm module.")]
@@ -56,6 +130,178 @@ pub enum Error {
     Generic(#[from] anyhow::Error),
 }
 
+impl Error {
+    /// Classifies a trap raised by the guest
+    pub fn from_trap(trap: TrapInfo) -> Self {
+        match trap.code {
+            Some(Trap::UnreachableCodeReached) => Error::Unreachable(trap),
+            Some(Trap::MemoryOutOfBounds) => Error::MemoryOutOfBounds(trap),
+            Some(Trap::StackOverflow) => Error::StackOverflow(trap),
+            Some(_) => Error::GuestTrap(trap),
+            None => Error::HostError(trap),
+        }
+    }
+
+    /// A short name of the kind of failure, such as `timeout` or `trap`, e.g. to label
+    /// metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::GuestCallTimeout { .. } | Error::InitializationFailedTimeout(_) => "timeout",
+            Error::FuelExhausted { .. } => "fuel",
+            Error::MemoryLimitExceeded(_) => "memory",
+            Error::Unreachable(_)
+            | Error::MemoryOutOfBounds(_)
+            | Error::StackOverflow(_)
+            | Error::GuestTrap(_) => "trap",
+            Error::HostError(_) | Error::HostCallFailure(_) => "host",
+            Error::GuestError(_) | Error::GuestCallFailure(_) => "guest",
+            Error::PermissionDenied { .. } => "permission",
+            Error::InitFailed(_) | Error::InitializationFailed(_) => "init",
+            _ => "other",
+        }
+    }
+
+    /// The details of the guest failure, if the error is one
+    pub fn trap(&self) -> Option<&TrapInfo> {
+        match self {
+            Error::Unreachable(trap)
+            | Error::MemoryOutOfBounds(trap)
+            | Error::StackOverflow(trap)
+            | Error::GuestTrap(trap)
+            | Error::HostError(trap)
+            | Error::GuestError(trap)
+            | Error::InitializationFailedTimeout(trap)
+            | Error::GuestCallTimeout { trap, .. }
+            | Error::FuelExhausted { trap, .. } => Some(trap),
+            _ => None,
+        }
+    }
+}
+
+/// Details of a guest failure: the trap code, when the guest trapped, and the wasm
+/// backtrace at the point of failure
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrapInfo {
+    /// The trap raised by wasmtime, `None` for host and guest-reported errors
+    pub code: Option<Trap>,
+    /// A description of the failure
+    pub message: String,
+    /// The guest frames, innermost first. Empty when no backtrace was captured.
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+impl TrapInfo {
+    /// A failure without trap code nor backtrace
+    pub fn new<T: Into<String>>(message: T) -> Self {
+        TrapInfo {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The details of an error returned by wasmtime while running the guest
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let code = error.downcast_ref::<Trap>().copied();
+        // wasmtime attaches the backtrace as the context of the trap, the root cause
+        // describes the failure itself
+        let message = error.root_cause().to_string();
+        let backtrace = error
+            .downcast_ref::<WasmBacktrace>()
+            .map(|backtrace| backtrace.frames().iter().map(BacktraceFrame::from).collect())
+            .unwrap_or_default();
+
+        TrapInfo {
+            code,
+            message,
+            backtrace,
+        }
+    }
+}
+
+impl TrapInfo {
+    /// The guest stack trace, one numbered frame per line, innermost first
+    pub fn format_backtrace(&self) -> String {
+        self.backtrace
+            .iter()
+            .enumerate()
+            .map(|(index, frame)| format!("{:>4}: {}\n", index, frame))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for TrapInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// A frame of a guest backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// The name of the module, from its name section
+    pub module: Option<String>,
+    /// The name of the function, from the name section
+    pub function: Option<String>,
+    /// The index of the function in the module
+    pub func_index: u32,
+    /// The offset of the instruction in the module
+    pub module_offset: Option<usize>,
+    /// The source file, from the DWARF debug info of the module
+    pub file: Option<String>,
+    /// The line in the source file
+    pub line: Option<u32>,
+    /// The column in the source file
+    pub column: Option<u32>,
+}
+
+impl From<&FrameInfo> for BacktraceFrame {
+    /// Symbolicates the frame with the DWARF debug info when the module has some, and with
+    /// the name section otherwise
+    fn from(frame: &FrameInfo) -> Self {
+        // inlined calls get a symbol each, the first one is the innermost
+        let symbol = frame.symbols().first();
+        BacktraceFrame {
+            module: frame.module_name().map(str::to_owned),
+            function: symbol
+                .and_then(|symbol| symbol.name())
+                .or_else(|| frame.func_name())
+                .map(str::to_owned),
+            func_index: frame.func_index(),
+            module_offset: frame.module_offset(),
+            file: symbol.and_then(|symbol| symbol.file()).map(str::to_owned),
+            line: symbol.and_then(|symbol| symbol.line()),
+            column: symbol.and_then(|symbol| symbol.column()),
+        }
+    }
+}
+
+/// `file:line function` when the frame could be symbolicated,
+/// `module!function @ offset` otherwise
+impl std::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let function = match &self.function {
+            Some(function) => function.clone(),
+            None => format!("<wasm function {}>", self.func_index),
+        };
+        if let Some(file) = &self.file {
+            write!(f, "{}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+            return write!(f, " {}", function);
+        }
+
+        write!(f, "{}!{}", self.module.as_deref().unwrap_or("<unknown>"), function)?;
+        if let Some(offset) = self.module_offset {
+            write!(f, " @ {:#x}", offset)?;
+        }
+        Ok(())
+    }
+}
+
 // impl From<Error> for wapc::errors::Error {
 //     fn from(e: Error) -> Self {
 //         wapc::errors::Error::ProviderFailure(Box::new(e))
@@ -71,4 +317,40 @@ mod tests {
     fn assert_sync_send() {
         needs_sync_send::<super::Error>();
     }
+
+    #[test]
+    fn classify_traps() {
+        use super::{Error, TrapInfo};
+        use wasmtime::Trap;
+
+        let trap = |code| TrapInfo {
+            code: Some(code),
+            ..Default::default()
+        };
+        assert!(matches!(Error::from_trap(trap(Trap::UnreachableCodeReached)), Error::Unreachable(_)));
+        assert!(matches!(Error::from_trap(trap(Trap::MemoryOutOfBounds)), Error::MemoryOutOfBounds(_)));
+        assert!(matches!(Error::from_trap(trap(Trap::StackOverflow)), Error::StackOverflow(_)));
+        assert!(matches!(Error::from_trap(trap(Trap::IntegerDivisionByZero)), Error::GuestTrap(_)));
+        assert!(matches!(Error::from_trap(TrapInfo::new("boom")), Error::HostError(_)));
+    }
+
+    #[test]
+    fn display_frames() {
+        use super::BacktraceFrame;
+
+        let mut frame = BacktraceFrame {
+            module: Some("myModule".to_owned()),
+            function: Some("assembly/index/test".to_owned()),
+            func_index: 12,
+            module_offset: Some(0x2a),
+            file: None,
+            line: None,
+            column: None,
+        };
+        assert_eq!(frame.to_string(), "myModule!assembly/index/test @ 0x2a");
+
+        frame.file = Some("assembly/index.ts".to_owned());
+        frame.line = Some(7);
+        assert_eq!(frame.to_string(), "assembly/index.ts:7 assembly/index/test");
+    }
 }
@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use wkr_common::resources::ResourceTable;
+use wkr_database::process_database_ops;
+use wkr_fetch::process_ops;
+use wapc_codec::messagepack::deserialize;
+
+/// The result a host binding hands back to the guest. `Ok` bytes are exposed through
+/// `__host_response`, the `Err` message through `__host_error`.
+pub type HostResult = std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A handler for the `__host_call` requests of a guest module.
+///
+/// A binding is registered for a `binding` name and, optionally, narrowed down to a
+/// `namespace` or to a single `operation` (see [`HostBindings`]).
+#[async_trait]
+pub trait HostBinding: Send + Sync {
+    /// Handle a single host call coming from the guest with the given `id`
+    async fn call(
+        &self,
+        id: u64,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        payload: &[u8],
+        resource_table: Arc<Mutex<ResourceTable>>,
+    ) -> HostResult;
+
+    /// The resource targeted by a host call, e.g. the host of an outgoing request, checked
+    /// against the [`Permissions`](crate::permissions::Permissions) scopes of the binding.
+    ///
+    /// Returns `None` when the call doesn't target a scoped resource.
+    fn scope(&self, _namespace: &str, _operation: &str, _payload: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+/// Registry of the [`HostBinding`]s reachable from a guest module.
+///
+/// Lookups go from the most specific registration to the least specific one:
+/// `binding:namespace:operation`, then `binding:namespace`, then `binding`.
+///
+/// The default registry contains the built-in `fetch` and `database` bindings, use
+/// [`HostBindings::new`] to start from an empty one.
+#[derive(Clone)]
+pub struct HostBindings {
+    handlers: HashMap<(String, Option<String>, Option<String>), Arc<dyn HostBinding>>,
+}
+
+impl HostBindings {
+    /// An empty registry, without any of the built-in bindings
+    #[must_use]
+    pub fn new() -> Self {
+        HostBindings {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register a handler for every host call made to `binding`
+    pub fn register<B: HostBinding + 'static>(&mut self, binding: &str, handler: B) -> &mut Self {
+        self.insert(binding, None, None, Arc::new(handler))
+    }
+
+    /// Register a handler for the host calls made to the `namespace` of `binding`
+    pub fn register_namespace<B: HostBinding + 'static>(
+        &mut self,
+        binding: &str,
+        namespace: &str,
+        handler: B,
+    ) -> &mut Self {
+        self.insert(binding, Some(namespace), None, Arc::new(handler))
+    }
+
+    /// Register a handler for a single `binding:namespace:operation` host call
+    pub fn register_operation<B: HostBinding + 'static>(
+        &mut self,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        handler: B,
+    ) -> &mut Self {
+        self.insert(binding, Some(namespace), Some(operation), Arc::new(handler))
+    }
+
+    /// Remove every handler registered for `binding`, whatever its namespace or operation
+    pub fn unregister(&mut self, binding: &str) -> &mut Self {
+        self.handlers.retain(|(b, _, _), _| b != binding);
+        self
+    }
+
+    /// Find the handler in charge of `binding:namespace:operation`, if any
+    pub fn get(&self, binding: &str, namespace: &str, operation: &str) -> Option<Arc<dyn HostBinding>> {
+        let keys = [
+            (binding.to_owned(), Some(namespace.to_owned()), Some(operation.to_owned())),
+            (binding.to_owned(), Some(namespace.to_owned()), None),
+            (binding.to_owned(), None, None),
+        ];
+        keys.iter().find_map(|key| self.handlers.get(key).cloned())
+    }
+
+    fn insert(
+        &mut self,
+        binding: &str,
+        namespace: Option<&str>,
+        operation: Option<&str>,
+        handler: Arc<dyn HostBinding>,
+    ) -> &mut Self {
+        let key = (
+            binding.to_owned(),
+            namespace.map(str::to_owned),
+            operation.map(str::to_owned),
+        );
+        self.handlers.insert(key, handler);
+        self
+    }
+}
+
+impl Default for HostBindings {
+    fn default() -> Self {
+        let mut bindings = HostBindings::new();
+        bindings
+            .register("fetch", FetchBinding)
+            .register("database", DatabaseBinding);
+        bindings
+    }
+}
+
+impl std::fmt::Debug for HostBindings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct ScopedUrl {
+    url: String,
+}
+
+/// The built-in `fetch` binding, backed by `wkr-fetch`
+pub struct FetchBinding;
+
+#[async_trait]
+impl HostBinding for FetchBinding {
+    async fn call(
+        &self,
+        id: u64,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        payload: &[u8],
+        resource_table: Arc<Mutex<ResourceTable>>,
+    ) -> HostResult {
+        process_ops(id, binding, namespace, operation, payload, resource_table).await
+    }
+
+    /// The host of the request being created. An unreadable request is reported with an
+    /// empty scope so it can't slip through a scoped permission.
+    fn scope(&self, namespace: &str, _operation: &str, payload: &[u8]) -> Option<String> {
+        match namespace {
+            "init" => {
+                let host = deserialize::<ScopedUrl>(payload)
+                    .ok()
+                    .and_then(|request| url::Url::parse(&request.url).ok())
+                    .and_then(|url| url.host_str().map(str::to_owned));
+                Some(host.unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The built-in `database` binding, backed by `wkr-database`
+pub struct DatabaseBinding;
+
+#[async_trait]
+impl HostBinding for DatabaseBinding {
+    async fn call(
+        &self,
+        id: u64,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        payload: &[u8],
+        resource_table: Arc<Mutex<ResourceTable>>,
+    ) -> HostResult {
+        process_database_ops(id, binding, namespace, operation, payload, resource_table).await
+    }
+
+    /// The URL of the connection being opened
+    fn scope(&self, namespace: &str, operation: &str, payload: &[u8]) -> Option<String> {
+        match (namespace, operation) {
+            ("connection", "open") => Some(
+                deserialize::<ScopedUrl>(payload)
+                    .map(|config| config.url)
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::common::abi;
+use crate::errors::{Error, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use wasmparser::{Encoding, ExternalKind, Parser, Payload, TypeRef};
+
+/// The functions the host exports to guests under the `wapc` namespace
+const WAPC_HOST_FUNCTIONS: [&str; 9] = [
+    abi::HOST_CONSOLE_LOG,
+    abi::HOST_CALL,
+    abi::GUEST_REQUEST_FN,
+    abi::HOST_RESPONSE_FN,
+    abi::HOST_RESPONSE_LEN_FN,
+    abi::GUEST_RESPONSE_FN,
+    abi::GUEST_ERROR_FN,
+    abi::HOST_ERROR_FN,
+    abi::HOST_ERROR_LEN_FN,
+];
+
+/// The kind of an imported or exported item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Function,
+    Table,
+    Memory,
+    Global,
+    Tag,
+}
+
+/// An item imported or exported by a module
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Item {
+    pub name: String,
+    pub kind: ItemKind,
+}
+
+/// A linear memory, declared or imported by a module. Sizes are in 64KiB pages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryInfo {
+    pub initial: u64,
+    pub maximum: Option<u64>,
+    pub shared: bool,
+    pub memory64: bool,
+    pub imported: bool,
+}
+
+/// A custom section of a module, such as `name` or DWARF `.debug_*` sections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CustomSection {
+    pub name: String,
+    pub size: usize,
+}
+
+/// What a module needs from the host and what it provides, as reported by [`inspect`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ModuleInfo {
+    /// Imports, grouped by namespace
+    pub imports: BTreeMap<String, Vec<Item>>,
+    pub exports: Vec<Item>,
+    /// Whether the module exports `__guest_call`, required to call it
+    pub has_guest_call: bool,
+    /// Whether the module exports `wapc_init`
+    pub has_wapc_init: bool,
+    /// Whether the module exports `_start`
+    pub has_start: bool,
+    pub memories: Vec<MemoryInfo>,
+    pub custom_sections: Vec<CustomSection>,
+    /// The waPC host functions the module imports
+    pub wapc_functions: Vec<String>,
+}
+
+impl ModuleInfo {
+    /// Whether the module can be run as a waPC guest
+    pub fn is_wapc_guest(&self) -> bool {
+        self.has_guest_call
+    }
+}
+
+/// Parses `module_bytes` and reports its imports, exports and memories, without compiling it
+pub fn inspect(module_bytes: &[u8]) -> Result<ModuleInfo> {
+    let invalid = |e: wasmparser::BinaryReaderError| Error::InvalidModule(e.to_string());
+    let mut info = ModuleInfo::default();
+
+    for payload in Parser::new(0).parse_all(module_bytes) {
+        match payload.map_err(invalid)? {
+            Payload::Version {
+                encoding: Encoding::Component,
+                ..
+            } => {
+                return Err(Error::InvalidModule(
+                    "components are not supported".to_owned(),
+                ));
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(invalid)?;
+                    let kind = match import.ty {
+                        TypeRef::Func(_) => ItemKind::Function,
+                        TypeRef::Table(_) => ItemKind::Table,
+                        TypeRef::Memory(memory) => {
+                            info.memories.push(MemoryInfo {
+                                initial: memory.initial,
+                                maximum: memory.maximum,
+                                shared: memory.shared,
+                                memory64: memory.memory64,
+                                imported: true,
+                            });
+                            ItemKind::Memory
+                        }
+                        TypeRef::Global(_) => ItemKind::Global,
+                        TypeRef::Tag(_) => ItemKind::Tag,
+                    };
+                    if import.module == crate::environment::HOST_NAMESPACE
+                        && WAPC_HOST_FUNCTIONS.contains(&import.name)
+                    {
+                        info.wapc_functions.push(import.name.to_owned());
+                    }
+                    info.imports
+                        .entry(import.module.to_owned())
+                        .or_default()
+                        .push(Item {
+                            name: import.name.to_owned(),
+                            kind,
+                        });
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let memory = memory.map_err(invalid)?;
+                    info.memories.push(MemoryInfo {
+                        initial: memory.initial,
+                        maximum: memory.maximum,
+                        shared: memory.shared,
+                        memory64: memory.memory64,
+                        imported: false,
+                    });
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(invalid)?;
+                    let kind = match export.kind {
+                        ExternalKind::Func => ItemKind::Function,
+                        ExternalKind::Table => ItemKind::Table,
+                        ExternalKind::Memory => ItemKind::Memory,
+                        ExternalKind::Global => ItemKind::Global,
+                        ExternalKind::Tag => ItemKind::Tag,
+                    };
+                    if kind == ItemKind::Function {
+                        match export.name {
+                            abi::GUEST_CALL => info.has_guest_call = true,
+                            abi::WAPC_INIT => info.has_wapc_init = true,
+                            abi::TINYGO_START => info.has_start = true,
+                            _ => {}
+                        }
+                    }
+                    info.exports.push(Item {
+                        name: export.name.to_owned(),
+                        kind,
+                    });
+                }
+            }
+            Payload::CustomSection(reader) => {
+                info.custom_sections.push(CustomSection {
+                    name: reader.name().to_owned(),
+                    size: reader.data().len(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspect_wapc_guest() {
+        let module = wat::parse_str(
+            r#"(module
+                (import "wapc" "__guest_request" (func (param i32 i32)))
+                (import "wapc" "__guest_response" (func (param i32 i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1 16)
+                (func (export "__guest_call") (param i32 i32) (result i32) i32.const 1)
+                (func (export "wapc_init"))
+            )"#,
+        )
+        .unwrap();
+
+        let info = inspect(&module).unwrap();
+        assert!(info.is_wapc_guest());
+        assert!(info.has_wapc_init);
+        assert!(!info.has_start);
+        assert_eq!(info.imports["wapc"].len(), 2);
+        assert_eq!(info.imports["wasi_snapshot_preview1"][0].name, "fd_write");
+        assert_eq!(info.wapc_functions, vec!["__guest_request", "__guest_response"]);
+        assert_eq!(info.memories[0].initial, 1);
+        assert_eq!(info.memories[0].maximum, Some(16));
+    }
+
+    #[test]
+    fn reject_invalid_modules() {
+        assert!(matches!(inspect(b"not wasm"), Err(Error::InvalidModule(_))));
+    }
+}
@@ -8,9 +8,24 @@ mod environment_state;
 pub mod errors;
 pub mod builder;
 pub mod environment;
+pub mod host_binding;
+pub mod inspect;
+pub mod limits;
+pub mod logging;
+pub mod module_cache;
+pub mod permissions;
+pub mod stats;
+pub mod stdio;
 mod common;
+mod ticker;
 
 pub use builder::EnvironmentBuilder;
+pub use host_binding::{HostBinding, HostBindings};
+pub use limits::ResourceLimits;
+pub use logging::{GuestLog, LogLevel};
+pub use module_cache::ModuleCache;
+pub use permissions::Permissions;
+pub use stdio::{GuestOutput, StdioCapture};
 pub use wasmtime;
 pub use wasmtime_wasi;
 
@@ -0,0 +1,96 @@
+use wasmtime::ResourceLimiter;
+
+/// Limits on the resources a guest module can allocate in its store
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResourceLimits {
+    /// Maximum size, in bytes, of each linear memory
+    pub memory_size: Option<usize>,
+    /// Maximum number of elements of each table
+    pub table_elements: Option<u32>,
+    /// Maximum number of instances alive in the store
+    pub instances: Option<usize>,
+}
+
+/// The [`ResourceLimiter`] of an environment's store.
+///
+/// Besides enforcing the [`ResourceLimits`], it keeps track of the memory allocated by the
+/// guest, and of the limit that was hit, if any. A denied growth doesn't trap by itself,
+/// the guest usually traps right after, which is then reported as
+/// [`Error::MemoryLimitExceeded`](crate::errors::Error::MemoryLimitExceeded).
+#[derive(Debug, Default)]
+pub struct StoreLimiter {
+    limits: ResourceLimits,
+    memory: usize,
+    peak_memory: usize,
+    exceeded: Option<String>,
+}
+
+impl StoreLimiter {
+    pub fn new(limits: ResourceLimits) -> Self {
+        StoreLimiter {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// The limits being enforced
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits
+    }
+
+    /// Bytes of linear memory currently allocated
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Highest amount of linear memory allocated since the last [`StoreLimiter::reset_peak`]
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    /// Starts tracking the peak memory from the memory currently allocated
+    pub fn reset_peak(&mut self) {
+        self.peak_memory = self.memory;
+    }
+
+    /// The description of the limit hit since the last call, if any
+    pub fn take_exceeded(&mut self) -> Option<String> {
+        self.exceeded.take()
+    }
+}
+
+impl ResourceLimiter for StoreLimiter {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        if let Some(limit) = self.limits.memory_size {
+            if desired > limit {
+                self.exceeded = Some(format!(
+                    "memory of {} bytes requested, the limit is {} bytes",
+                    desired, limit
+                ));
+                return false;
+            }
+        }
+        self.memory = self.memory + desired - current;
+        self.peak_memory = self.peak_memory.max(self.memory);
+        true
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        match self.limits.table_elements {
+            Some(limit) if desired > limit => {
+                self.exceeded = Some(format!(
+                    "table of {} elements requested, the limit is {} elements",
+                    desired, limit
+                ));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits
+            .instances
+            .unwrap_or(wasmtime::DEFAULT_INSTANCE_LIMIT)
+    }
+}
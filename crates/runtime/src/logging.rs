@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Maximum number of records kept per invocation, the next ones are only emitted
+pub const MAX_LOGS_PER_INVOCATION: usize = 1000;
+
+/// The level of a guest log record
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// A record logged by the guest through `__console_log`.
+///
+/// The guest either logs a plain message, recorded at the `info` level, or a JSON object
+/// such as `{"level": "warn", "message": "slow query", "fields": {"ms": 250}}`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GuestLog {
+    #[serde(default)]
+    pub level: LogLevel,
+    pub message: String,
+    #[serde(default)]
+    pub fields: Map<String, Value>,
+}
+
+impl GuestLog {
+    /// Reads a `__console_log` payload, structured or not
+    pub fn parse(payload: &str) -> Self {
+        if payload.trim_start().starts_with('{') {
+            if let Ok(log) = serde_json::from_str::<GuestLog>(payload) {
+                return log;
+            }
+        }
+        GuestLog {
+            message: payload.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Emits the record as a `tracing` event, in the span of the current invocation
+    pub fn emit(&self) {
+        let fields = Value::Object(self.fields.clone());
+        match self.level {
+            LogLevel::Trace => tracing::trace!(target: "wkr::guest", %fields, "{}", self.message),
+            LogLevel::Debug => tracing::debug!(target: "wkr::guest", %fields, "{}", self.message),
+            LogLevel::Info => tracing::info!(target: "wkr::guest", %fields, "{}", self.message),
+            LogLevel::Warn => tracing::warn!(target: "wkr::guest", %fields, "{}", self.message),
+            LogLevel::Error => tracing::error!(target: "wkr::guest", %fields, "{}", self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_payloads() {
+        let log = GuestLog::parse("hello world");
+        assert_eq!(log.level, LogLevel::Info);
+        assert_eq!(log.message, "hello world");
+
+        let log = GuestLog::parse(r#"{"level":"warn","message":"slow","fields":{"ms":250}}"#);
+        assert_eq!(log.level, LogLevel::Warn);
+        assert_eq!(log.message, "slow");
+        assert_eq!(log.fields["ms"], 250);
+
+        // not a log record, kept as is
+        let log = GuestLog::parse(r#"{"ms":250}"#);
+        assert_eq!(log.level, LogLevel::Info);
+        assert_eq!(log.message, r#"{"ms":250}"#);
+    }
+}
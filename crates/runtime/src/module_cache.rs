@@ -0,0 +1,148 @@
+use crate::errors::Result;
+use crate::stats::COMPILE_DURATION;
+use metrics::histogram;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use wasmtime::{Engine, Module};
+
+const ARTIFACT_EXTENSION: &str = "cwasm";
+
+/// Feeds a [`Hash`] value to a sha256 digest
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.0.clone().finalize()[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// A content-addressed directory of precompiled modules.
+///
+/// Artifacts are keyed by the sha256 of the wasm bytes and by the configuration of the
+/// engine compiling them, so an engine only ever loads artifacts it is compatible with.
+/// An artifact that can't be loaded, e.g. a corrupt file or one produced by another
+/// version of wasmtime, is dropped and the module is compiled again.
+#[derive(Debug, Clone)]
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    /// A cache storing its artifacts in `dir`, created on the first write
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        ModuleCache { dir: dir.into() }
+    }
+
+    /// The directory holding the artifacts
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The key of the artifact of `module_bytes` compiled by `engine`
+    pub fn key(&self, engine: &Engine, module_bytes: &[u8]) -> String {
+        // `DefaultHasher` isn't stable across Rust releases, the key must be
+        let mut hasher = Sha256Hasher(Sha256::new());
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        format!(
+            "{}-{}",
+            hex::encode(Sha256::digest(module_bytes)),
+            hex::encode(hasher.0.finalize())
+        )
+    }
+
+    /// Where the artifact of `module_bytes` compiled by `engine` is stored
+    pub fn path(&self, engine: &Engine, module_bytes: &[u8]) -> PathBuf {
+        self.dir
+            .join(self.key(engine, module_bytes))
+            .with_extension(ARTIFACT_EXTENSION)
+    }
+
+    /// Loads the module from its artifact, compiling and storing it on a cache miss.
+    ///
+    /// Failing to store the artifact is not an error, the compiled module is returned
+    /// anyway.
+    pub fn load(&self, engine: &Engine, module_bytes: &[u8]) -> Result<Module> {
+        let path = self.path(engine, module_bytes);
+        if path.exists() {
+            // SAFETY: artifacts are only ever written by `store`, from modules compiled by
+            // wasmtime, and are replaced atomically so a reader never sees a partial file.
+            match unsafe { Module::deserialize_file(engine, &path) } {
+                Ok(module) => return Ok(module),
+                Err(e) => {
+                    warn!("Discarding module artifact {}: {}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        let module = compile_module(engine, module_bytes)?;
+        if let Err(e) = self.store(&path, &module) {
+            warn!("Cannot write module artifact {}: {}", path.display(), e);
+        }
+        Ok(module)
+    }
+
+    /// Compiles `module_bytes` and stores the artifact, replacing any previous one.
+    /// Returns the path of the artifact.
+    pub fn compile(&self, engine: &Engine, module_bytes: &[u8]) -> Result<PathBuf> {
+        let path = self.path(engine, module_bytes);
+        let module = compile_module(engine, module_bytes)?;
+        self.store(&path, &module)?;
+        Ok(path)
+    }
+
+    fn store(&self, path: &Path, module: &Module) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let bytes = module.serialize()?;
+        // write next to the final file and rename, so a concurrent reader either sees the
+        // previous artifact or the complete new one
+        let tmp = path.with_extension(format!("{}.{}.tmp", ARTIFACT_EXTENSION, std::process::id()));
+        fs::write(&tmp, bytes)?;
+        if let Err(e) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+/// Compiles a module, recording how long it took
+fn compile_module(engine: &Engine, module_bytes: &[u8]) -> Result<Module> {
+    let start = Instant::now();
+    let module = Module::new(engine, module_bytes)?;
+    histogram!(COMPILE_DURATION, start.elapsed().as_secs_f64());
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recompile_corrupt_artifacts() {
+        let dir = std::env::temp_dir().join(format!("wkr-module-cache-{}", std::process::id()));
+        let cache = ModuleCache::new(dir.clone());
+        let engine = Engine::default();
+        let module = wat::parse_str(r#"(module (func (export "noop")))"#).unwrap();
+
+        fs::create_dir_all(&dir).unwrap();
+        let path = cache.path(&engine, &module);
+        fs::write(&path, b"not an artifact").unwrap();
+
+        let loaded = cache.load(&engine, &module).unwrap();
+        assert!(loaded.get_export("noop").is_some());
+        // the corrupt artifact was replaced with the compiled module
+        assert!(unsafe { Module::deserialize_file(&engine, &path) }.is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
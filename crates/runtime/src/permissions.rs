@@ -0,0 +1,151 @@
+use crate::errors::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The capabilities granted to a guest module.
+///
+/// * `allow`: the host calls the guest may perform, written as `binding`,
+///   `binding:namespace` or `binding:namespace:operation`. Any segment can be `*`.
+/// * `scopes`: per binding allow-lists for the resource a host call targets, such as the
+///   host of a `fetch` request or the URL of a `database` connection. Patterns may contain
+///   `*` wildcards, e.g. `*.example.com`.
+///
+/// A binding without an entry in `scopes` is not restricted beyond `allow`.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    /// Host calls the guest is allowed to perform.
+    pub allow: Vec<String>,
+    /// Resources each binding is allowed to reach.
+    pub scopes: HashMap<String, Vec<String>>,
+}
+
+impl Permissions {
+    /// Permissions that don't allow any host call
+    #[must_use]
+    pub fn new() -> Self {
+        Permissions::default()
+    }
+
+    /// Allow the host calls matching `pattern`
+    #[must_use]
+    pub fn allow<T: Into<String>>(mut self, pattern: T) -> Self {
+        self.allow.push(pattern.into());
+        self
+    }
+
+    /// Restrict the resources `binding` can reach to the ones matching `pattern`
+    #[must_use]
+    pub fn scope<T: Into<String>>(mut self, binding: &str, pattern: T) -> Self {
+        self.scopes
+            .entry(binding.to_owned())
+            .or_default()
+            .push(pattern.into());
+        self
+    }
+
+    /// Checks whether the guest may perform the `binding:namespace:operation` host call.
+    ///
+    /// `scope` is the resource targeted by the call, as reported by the host binding, if
+    /// the call targets one.
+    pub fn check(
+        &self,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        scope: Option<&str>,
+    ) -> Result<(), Error> {
+        let denied = |reason: String| Error::PermissionDenied {
+            binding: binding.to_owned(),
+            namespace: namespace.to_owned(),
+            operation: operation.to_owned(),
+            reason,
+        };
+
+        let allowed = self
+            .allow
+            .iter()
+            .any(|pattern| call_matches(pattern, binding, namespace, operation));
+        if !allowed {
+            return Err(denied("host call not allowed".to_owned()));
+        }
+
+        if let (Some(patterns), Some(scope)) = (self.scopes.get(binding), scope) {
+            if !patterns.iter().any(|pattern| wildcard_match(pattern, scope)) {
+                return Err(denied(format!("`{}` is out of scope", scope)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn call_matches(pattern: &str, binding: &str, namespace: &str, operation: &str) -> bool {
+    let mut segments = pattern.splitn(3, ':');
+    [binding, namespace, operation]
+        .iter()
+        .all(|value| match segments.next() {
+            None | Some("*") => true,
+            Some(segment) => segment == *value,
+        })
+}
+
+/// Matches `value` against `pattern`, where `*` stands for any sequence of characters
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // no wildcard in the pattern
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allow_patterns() {
+        let permissions = Permissions::new().allow("fetch").allow("database:command:query");
+
+        assert!(permissions.check("fetch", "init", "", None).is_ok());
+        assert!(permissions.check("database", "command", "query", None).is_ok());
+        assert!(permissions.check("database", "command", "execute", None).is_err());
+        assert!(permissions.check("storage", "file", "get", None).is_err());
+    }
+
+    #[test]
+    fn scopes() {
+        let permissions = Permissions::new()
+            .allow("fetch:*")
+            .scope("fetch", "api.example.com")
+            .scope("fetch", "*.internal");
+
+        assert!(permissions.check("fetch", "init", "", Some("api.example.com")).is_ok());
+        assert!(permissions.check("fetch", "init", "", Some("db.internal")).is_ok());
+        assert!(permissions.check("fetch", "init", "", Some("example.com")).is_err());
+        assert!(permissions.check("fetch", "send", "", None).is_ok());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("sqlite://*.db", "sqlite://data/app.db"));
+        assert!(!wildcard_match("sqlite://*.db", "sqlite://data/app.sqlite"));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("exact", "exactly"));
+    }
+}
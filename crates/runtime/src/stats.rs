@@ -0,0 +1,119 @@
+//! Metrics recorded by the runtime through the `metrics` crate. They go nowhere until a
+//! recorder is installed, such as the Prometheus exporter of the server.
+
+use metrics::{describe_counter, describe_histogram, histogram, increment_counter, Unit};
+use std::time::Duration;
+
+use crate::environment::InvocationResult;
+use crate::errors::Error;
+
+/// Calls of guest operations, by `function` and `version`
+pub const INVOCATIONS: &str = "wkr_function_invocations_total";
+/// Failed calls of guest operations, by `function`, `version` and error `kind`
+pub const INVOCATION_ERRORS: &str = "wkr_function_errors_total";
+/// Duration of the calls of guest operations, by `function` and `version`
+pub const INVOCATION_DURATION: &str = "wkr_function_duration_seconds";
+/// Fuel consumed by each call, by `function` and `version`
+pub const FUEL_CONSUMED: &str = "wkr_function_fuel_consumed";
+/// Peak linear memory of each call, by `function` and `version`
+pub const PEAK_MEMORY: &str = "wkr_function_peak_memory_bytes";
+/// Duration of the initialization of the instances, by `function` and `version`
+pub const INIT_DURATION: &str = "wkr_function_init_duration_seconds";
+/// Instances handed to a call, by `function` and `start`: `cold` when the instance was
+/// created for the call, `warm` when it was waiting in a pool
+pub const STARTS: &str = "wkr_function_starts_total";
+/// Duration of the compilation of modules missing from the module cache
+pub const COMPILE_DURATION: &str = "wkr_module_compile_seconds";
+/// Host calls of the guests, by `binding`, `namespace`, `operation` and `outcome`
+pub const HOST_CALLS: &str = "wkr_host_calls_total";
+/// Duration of the host calls, by `binding`, `namespace` and `operation`
+pub const HOST_CALL_DURATION: &str = "wkr_host_call_duration_seconds";
+
+/// Registers the descriptions of the runtime metrics with the installed recorder
+pub fn describe() {
+    describe_counter!(INVOCATIONS, "Calls of guest operations");
+    describe_counter!(INVOCATION_ERRORS, "Failed calls of guest operations");
+    describe_histogram!(INVOCATION_DURATION, Unit::Seconds, "Duration of the calls of guest operations");
+    describe_histogram!(FUEL_CONSUMED, "Fuel consumed by each call");
+    describe_histogram!(PEAK_MEMORY, Unit::Bytes, "Peak linear memory of each call");
+    describe_histogram!(INIT_DURATION, Unit::Seconds, "Duration of the initialization of the instances");
+    describe_counter!(STARTS, "Instances handed to a call, cold or warm");
+    describe_histogram!(COMPILE_DURATION, Unit::Seconds, "Duration of the compilation of modules");
+    describe_counter!(HOST_CALLS, "Host calls of the guests");
+    describe_histogram!(HOST_CALL_DURATION, Unit::Seconds, "Duration of the host calls");
+}
+
+/// Records a call of `function`, successful or not
+pub(crate) fn record_invocation(
+    function: &str,
+    version: &str,
+    duration: Duration,
+    result: &Result<InvocationResult, Error>,
+) {
+    let function = function.to_owned();
+    let version = version.to_owned();
+    increment_counter!(INVOCATIONS, "function" => function.clone(), "version" => version.clone());
+    histogram!(
+        INVOCATION_DURATION,
+        duration.as_secs_f64(),
+        "function" => function.clone(),
+        "version" => version.clone()
+    );
+    match result {
+        Ok(result) => {
+            histogram!(
+                FUEL_CONSUMED,
+                result.fuel_consumed as f64,
+                "function" => function.clone(),
+                "version" => version.clone()
+            );
+            histogram!(
+                PEAK_MEMORY,
+                result.peak_memory as f64,
+                "function" => function,
+                "version" => version
+            );
+        }
+        Err(e) => increment_counter!(
+            INVOCATION_ERRORS,
+            "function" => function,
+            "version" => version,
+            "kind" => e.kind()
+        ),
+    }
+}
+
+/// Records the initialization of an instance of `function`
+pub(crate) fn record_init(function: &str, version: &str, duration: Duration) {
+    histogram!(
+        INIT_DURATION,
+        duration.as_secs_f64(),
+        "function" => function.to_owned(),
+        "version" => version.to_owned()
+    );
+}
+
+/// Records a host call of the guest
+pub(crate) fn record_host_call(
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    duration: Duration,
+    success: bool,
+) {
+    let outcome = if success { "ok" } else { "error" };
+    increment_counter!(
+        HOST_CALLS,
+        "binding" => binding.to_owned(),
+        "namespace" => namespace.to_owned(),
+        "operation" => operation.to_owned(),
+        "outcome" => outcome
+    );
+    histogram!(
+        HOST_CALL_DURATION,
+        duration.as_secs_f64(),
+        "binding" => binding.to_owned(),
+        "namespace" => namespace.to_owned(),
+        "operation" => operation.to_owned()
+    );
+}
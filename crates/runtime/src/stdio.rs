@@ -0,0 +1,133 @@
+use std::io::{self, Write};
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasmtime_wasi::WasiCtx;
+
+/// Default amount of output kept per stream and per invocation
+pub const DEFAULT_STDIO_LIMIT: usize = 64 * 1024;
+
+/// How the stdio of a guest is captured
+#[derive(Clone, Copy, Debug)]
+pub struct StdioCapture {
+    /// Bytes kept per stream and per invocation, the rest is dropped
+    pub limit: usize,
+    /// Also emit the captured output as `tracing` events, tagged with the function id and
+    /// the request id
+    pub forward: bool,
+}
+
+impl Default for StdioCapture {
+    fn default() -> Self {
+        StdioCapture {
+            limit: DEFAULT_STDIO_LIMIT,
+            forward: false,
+        }
+    }
+}
+
+/// What the guest wrote to its stdout and stderr during an invocation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GuestOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether some of the output went over the limit and was dropped
+    pub truncated: bool,
+}
+
+impl GuestOutput {
+    /// Emits the output as `tracing` events, one per line
+    pub fn forward(&self, function_id: &str, request_id: &str) {
+        for line in String::from_utf8_lossy(&self.stdout).lines() {
+            tracing::info!(
+                target: "wkr::guest",
+                function_id,
+                request_id,
+                stream = "stdout",
+                "{}",
+                line
+            );
+        }
+        for line in String::from_utf8_lossy(&self.stderr).lines() {
+            tracing::warn!(
+                target: "wkr::guest",
+                function_id,
+                request_id,
+                stream = "stderr",
+                "{}",
+                line
+            );
+        }
+        if self.truncated {
+            tracing::warn!(target: "wkr::guest", function_id, request_id, "guest output truncated");
+        }
+    }
+}
+
+/// Keeps the first `limit` bytes written to it. Writes never fail, so a guest writing too
+/// much isn't disturbed.
+#[derive(Debug)]
+struct CappedBuffer {
+    bytes: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+impl CappedBuffer {
+    fn new(limit: usize) -> Self {
+        CappedBuffer {
+            bytes: Vec::new(),
+            limit,
+            truncated: false,
+        }
+    }
+}
+
+impl Write for CappedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let available = self.limit.saturating_sub(self.bytes.len());
+        if buf.len() > available {
+            self.truncated = true;
+        }
+        self.bytes.extend_from_slice(&buf[..buf.len().min(available)]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory pipes standing for the stdio of a guest for the duration of an invocation
+pub(crate) struct StdioPipes {
+    stdout: WritePipe<CappedBuffer>,
+    stderr: WritePipe<CappedBuffer>,
+}
+
+impl StdioPipes {
+    /// Replaces the stdio of `ctx` with fresh pipes, `stdin` being the input of the guest
+    pub(crate) fn install(ctx: &mut WasiCtx, limit: usize, stdin: Vec<u8>) -> Self {
+        let stdout = WritePipe::new(CappedBuffer::new(limit));
+        let stderr = WritePipe::new(CappedBuffer::new(limit));
+        ctx.set_stdin(Box::new(ReadPipe::from(stdin)));
+        ctx.set_stdout(Box::new(stdout.clone()));
+        ctx.set_stderr(Box::new(stderr.clone()));
+        StdioPipes { stdout, stderr }
+    }
+
+    /// Detaches the pipes from `ctx` and returns what the guest wrote to them
+    pub(crate) fn collect(self, ctx: &mut WasiCtx) -> GuestOutput {
+        // the context must drop its handles for the buffers to be taken back
+        ctx.set_stdin(Box::new(ReadPipe::from(Vec::new())));
+        ctx.set_stdout(Box::new(WritePipe::new(io::sink())));
+        ctx.set_stderr(Box::new(WritePipe::new(io::sink())));
+
+        let stdout = self.stdout.try_into_inner().ok();
+        let stderr = self.stderr.try_into_inner().ok();
+        let truncated = stdout.as_ref().map_or(false, |b| b.truncated)
+            || stderr.as_ref().map_or(false, |b| b.truncated);
+        GuestOutput {
+            stdout: stdout.map(|b| b.bytes).unwrap_or_default(),
+            stderr: stderr.map(|b| b.bytes).unwrap_or_default(),
+            truncated,
+        }
+    }
+}
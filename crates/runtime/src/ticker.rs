@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use wasmtime::Engine;
+
+use crate::errors::{Error, Result};
+
+/// The tickers currently running, at most one per engine
+static TICKERS: Mutex<Vec<Weak<EpochTicker>>> = Mutex::new(Vec::new());
+
+/// Increments the epoch of an [`Engine`] at a fixed interval, so the
+/// [`EpochDeadlines`](crate::environment::EpochDeadlines) of its stores expire.
+///
+/// Tickers are shared: every environment of an engine holds the same ticker, which stops
+/// once the last of them is dropped. A ticker is a task of the Tokio runtime it was
+/// started on.
+pub(crate) struct EpochTicker {
+    engine: Engine,
+    tick: Duration,
+}
+
+impl EpochTicker {
+    /// The ticker of `engine`, started with the given `tick` if it isn't running yet.
+    ///
+    /// Fails when called outside of a Tokio runtime.
+    pub(crate) fn for_engine(engine: &Engine, tick: Duration) -> Result<Arc<EpochTicker>> {
+        let mut tickers = TICKERS.lock().unwrap();
+        tickers.retain(|ticker| ticker.strong_count() > 0);
+
+        let running = tickers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|ticker| Engine::same(&ticker.engine, engine));
+        if let Some(ticker) = running {
+            if ticker.tick != tick {
+                warn!(
+                    "Engine epoch already ticking every {:?}, ignoring a tick of {:?}",
+                    ticker.tick, tick
+                );
+            }
+            return Ok(ticker);
+        }
+
+        let ticker = EpochTicker::start(engine.clone(), tick)?;
+        tickers.push(Arc::downgrade(&ticker));
+        Ok(ticker)
+    }
+
+    fn start(engine: Engine, tick: Duration) -> Result<Arc<Self>> {
+        let runtime = Handle::try_current().map_err(|e| {
+            Error::General(format!("the epoch ticker needs a Tokio runtime: {}", e))
+        })?;
+        let ticker = Arc::new(EpochTicker { engine, tick });
+        let weak = Arc::downgrade(&ticker);
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(tick);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(ticker) => ticker.engine.increment_epoch(),
+                    None => break,
+                }
+            }
+        });
+        Ok(ticker)
+    }
+}
@@ -43,6 +43,7 @@ pub(crate) fn init_ctx(
 ) -> Result<WasiCtx, Box<dyn Error + Send + Sync>> {
   let mut ctx_builder = wasi_cap_std_sync::WasiCtxBuilder::new();
 
+  // only kept when the environment doesn't capture the stdio, which it does by default
   ctx_builder = ctx_builder.inherit_stdio().args(argv)?.envs(env)?;
 
   for (name, file) in preopen_dirs {
@@ -52,24 +53,30 @@ pub(crate) fn init_ctx(
   Ok(ctx_builder.build())
 }
 
+/// Opens the directories exposed to the guest, failing on the first one that is missing
+/// or can't be read
 pub(crate) fn compute_preopen_dirs(
   dirs: &[String],
   map_dirs: &[(String, String)],
-) -> Result<Vec<(String, Dir)>, Box<dyn Error>> {
-  let ambient_authority = ambient_authority();
+) -> Result<Vec<(String, Dir)>, Box<dyn Error + Send + Sync>> {
   let mut preopen_dirs = Vec::new();
 
   for dir in dirs.iter() {
-    preopen_dirs.push((dir.clone(), Dir::open_ambient_dir(dir, ambient_authority)?));
+    preopen_dirs.push((dir.clone(), open_dir(dir)?));
   }
 
   for (guest, host) in map_dirs.iter() {
-    preopen_dirs.push((guest.clone(), Dir::open_ambient_dir(host, ambient_authority)?));
+    preopen_dirs.push((guest.clone(), open_dir(host)?));
   }
 
   Ok(preopen_dirs)
 }
 
+fn open_dir(path: &str) -> Result<Dir, Box<dyn Error + Send + Sync>> {
+  Dir::open_ambient_dir(path, ambient_authority())
+    .map_err(|e| format!("cannot open directory `{}`: {}", path, e).into())
+}
+
 #[allow(dead_code)]
 pub(crate) fn compute_argv(module: &Path, module_args: &[String]) -> Vec<String> {
   // Add argv[0], which is the program name. Only include the base name of the
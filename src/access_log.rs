@@ -0,0 +1,231 @@
+use axum::{
+    body::{boxed, Body, BoxBody, Bytes, HttpBody},
+    http::{header::CONTENT_LENGTH, HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use http_body::SizeHint;
+use serde::Serialize;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Header carrying the id of a request, kept when the client sends one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id accepted from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// What the server learns about a request while handling it, shared with the handlers
+/// through the extensions of the request
+#[derive(Debug)]
+pub struct RequestContext {
+    /// The id of the request, handed to the guest and stamped on the logs of its calls
+    pub id: String,
+    function: Mutex<Option<(String, u64)>>,
+    bytes_in: AtomicU64,
+}
+
+impl RequestContext {
+    pub fn new(id: String) -> Self {
+        RequestContext {
+            id,
+            function: Mutex::new(None),
+            bytes_in: AtomicU64::new(0),
+        }
+    }
+
+    /// The context of `request`, or a new one if it didn't go through [`access_log`]
+    pub fn of<B>(request: &Request<B>) -> Arc<Self> {
+        request
+            .extensions()
+            .get::<Arc<Self>>()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(RequestContext::new(Uuid::new_v4().to_string())))
+    }
+
+    /// Records the function version handling the request
+    pub fn set_function(&self, name: &str, version: u64) {
+        *self.function.lock().unwrap() = Some((name.to_owned(), version));
+    }
+
+    /// Records that `bytes` more bytes of the request body were read
+    pub fn add_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Gives each request an id, taken from its `x-request-id` header when valid, and writes a
+/// JSON access log line to stdout once its response is sent
+pub async fn access_log(mut request: Request<Body>, next: Next<Body>) -> Response {
+    let start = Instant::now();
+    let context = Arc::new(RequestContext::new(request_id(request.headers())));
+    request.extensions_mut().insert(context.clone());
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let method = request.method().to_string();
+    let path = request.uri().path().to_owned();
+
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&context.id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    let pending = PendingLog {
+        start,
+        context,
+        method,
+        path,
+        status: response.status().as_u16(),
+        content_length,
+    };
+    response.map(|body| {
+        boxed(LoggedBody {
+            inner: body,
+            bytes_out: 0,
+            pending: Some(pending),
+        })
+    })
+}
+
+/// The id sent by the client, unless missing or unfit for logs, or a new one
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+#[derive(Serialize)]
+struct AccessLog<'a> {
+    timestamp: String,
+    request_id: &'a str,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    duration_ms: f64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// The access log of a request, written once its response body is sent or dropped
+struct PendingLog {
+    start: Instant,
+    context: Arc<RequestContext>,
+    method: String,
+    path: String,
+    status: u16,
+    content_length: u64,
+}
+
+impl PendingLog {
+    fn write(self, bytes_out: u64) {
+        let (function, version) = match self.context.function.lock().unwrap().take() {
+            Some((function, version)) => (Some(function), Some(version)),
+            None => (None, None),
+        };
+        // the body read by a function, the announced one otherwise
+        let bytes_in = match self.context.bytes_in.load(Ordering::Relaxed) {
+            0 => self.content_length,
+            bytes_in => bytes_in,
+        };
+        let entry = AccessLog {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            request_id: &self.context.id,
+            method: &self.method,
+            path: &self.path,
+            status: self.status,
+            function,
+            version,
+            duration_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+            bytes_in,
+            bytes_out,
+        };
+
+        let mut stdout = std::io::stdout().lock();
+        if serde_json::to_writer(&mut stdout, &entry).is_ok() {
+            let _ = writeln!(stdout);
+        }
+    }
+}
+
+/// A response body counting the bytes sent, which writes the access log when dropped
+struct LoggedBody {
+    inner: BoxBody,
+    bytes_out: u64,
+    pending: Option<PendingLog>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes_out += chunk.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.write(self.bytes_out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids() {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-42"));
+        assert_eq!(request_id(&headers), "req-42");
+
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("two words"));
+        let generated = request_id(&headers);
+        assert_ne!(generated, "two words");
+        assert!(Uuid::parse_str(&generated).is_ok());
+    }
+}
@@ -0,0 +1,16 @@
+use wasmtest::server::{serve, ServerOptions};
+use wasmtest::telemetry;
+
+#[tokio::main]
+async fn main() {
+    // initialize logs and tracing
+    if let Err(err) = telemetry::init() {
+        eprintln!("Tracing error: {}", err);
+        return;
+    }
+
+    if let Err(err) = serve(ServerOptions::default()).await {
+        eprintln!("Server error: {}", err);
+    }
+    telemetry::shutdown();
+}
@@ -1,8 +1,17 @@
-use axum::{response::{IntoResponse, Response}, http::StatusCode, Json};
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde::Serialize;
-use serde_json::json;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 // use std::convert::Infallible;
 use thiserror::Error;
+use wkr_core::errors::Error as RuntimeError;
+use crate::registry::RegistryError;
 // use hyper::{http::StatusCode, Rejection, Reply};
 
 #[derive(Error, Debug)]
@@ -86,56 +95,206 @@ struct ErrorResponse {
 //     Ok(warp::reply::with_status(json, code))
 // }
 
+/// Whether error responses include the failures of the guests: their messages and
+/// backtraces. Only meant for development, as these can leak internals of the functions.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Includes the details of guest failures in error responses, see [`DEBUG`]
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::Relaxed);
+}
+
+/// Content type of the error responses
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Body of the error responses, the problem details of RFC 7807
+#[derive(Serialize, Debug)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// The id of the request the function failed on, to find its logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// The wasm backtrace of the guest failure, in debug mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backtrace: Option<String>,
+}
+
 /// Our app's top level error type.
 pub enum AppError {
-    /// Something went wrong when calling the user repo.
-    UserRepo(UserRepoError),
+    /// Something went wrong when managing or calling a function.
+    Function(FunctionError),
 }
 
-/// This makes it possible to use `?` to automatically convert a `UserRepoError`
+/// This makes it possible to use `?` to automatically convert a `FunctionError`
 /// into an `AppError`.
-impl From<UserRepoError> for AppError {
-    fn from(inner: UserRepoError) -> Self {
-        AppError::UserRepo(inner)
+impl From<FunctionError> for AppError {
+    fn from(inner: FunctionError) -> Self {
+        AppError::Function(inner)
     }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::UserRepo(UserRepoError::NotFound) => {
-                (StatusCode::NOT_FOUND, "User not found")
+        let AppError::Function(error) = self;
+        let debug = DEBUG.load(Ordering::Relaxed);
+        let mut request_id = None;
+        let mut backtrace = None;
+
+        let (status, title, detail) = match error {
+            FunctionError::NotFound => (StatusCode::NOT_FOUND, "Function not found", None),
+            FunctionError::InvalidConfig(e) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Invalid function configuration", Some(e))
+            }
+            FunctionError::RouteNotFound => {
+                (StatusCode::NOT_FOUND, "No route matches the request", None)
+            }
+            FunctionError::InvalidRoute(e) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Invalid route", Some(e))
+            }
+            FunctionError::InvalidUpload(e) => (StatusCode::BAD_REQUEST, "Invalid upload", Some(e)),
+            FunctionError::InvalidModule(e) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Invalid module", Some(e))
             }
-            AppError::UserRepo(UserRepoError::InvalidUsername) => {
-                (StatusCode::UNPROCESSABLE_ENTITY, "Invalid username")
+            FunctionError::InvalidGuestResponse(e) => {
+                (StatusCode::BAD_GATEWAY, "Invalid function response", Some(e))
             }
-            AppError::UserRepo(UserRepoError::InvalidFunction) => {
-                (StatusCode::UNPROCESSABLE_ENTITY, "Invalid function")
+            FunctionError::Registry(e) => match e {
+                RegistryError::NotFound(_) => (StatusCode::NOT_FOUND, "Function not found", None),
+                RegistryError::InvalidName(name) => {
+                    (StatusCode::UNPROCESSABLE_ENTITY, "Invalid function name", Some(name))
+                }
+                e => {
+                    tracing::error!("{}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, "Function registry failure", None)
+                }
+            },
+            FunctionError::FunctionUnavailable(e) => {
+                tracing::error!("cannot start function: {:#}", e);
+                let (status, title) = match e.downcast_ref::<RuntimeError>() {
+                    Some(error) => function_failure(error),
+                    None => (StatusCode::INTERNAL_SERVER_ERROR, "Function could not be started"),
+                };
+                (status, title, debug.then(|| format!("{:#}", e)))
             }
-            AppError::UserRepo(UserRepoError::FailFunctionExecution) => {
-                (StatusCode::UNPROCESSABLE_ENTITY, "Fail function execution")
+            FunctionError::FunctionFailed { request_id: id, error } => {
+                let trace = error.trap().map(|trap| trap.format_backtrace()).unwrap_or_default();
+                tracing::error!(request_id = %id, "{}\n{}", error, trace);
+                let (status, title) = function_failure(&error);
+                request_id = Some(id);
+                if debug && !trace.is_empty() {
+                    backtrace = Some(trace);
+                }
+                (status, title, debug.then(|| error.to_string()))
+            }
+            FunctionError::Internal(e) => {
+                tracing::error!("{}", e);
+                let detail = debug.then_some(e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error", detail)
             }
         };
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
-
-        (status, body).into_response()
+        let problem = Problem {
+            kind: "about:blank",
+            title,
+            status: status.as_u16(),
+            detail,
+            request_id,
+            backtrace,
+        };
+        let mut response = (status, Json(problem)).into_response();
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        response
     }
 }
 
-/// Errors that can happen when using the user repo.
-// #[derive(Debug)]
+/// Errors of the function endpoints: publishing, routing and calling the functions.
 #[derive(Error, Debug)]
-pub enum UserRepoError {
-    #[error("error while sending message to broadcaster")]
+pub enum FunctionError {
+    #[error("function not found")]
     NotFound,
-    #[error("error while sending message to broadcaster")]
-    InvalidUsername,
+    #[error("invalid function configuration: {0}")]
+    InvalidConfig(String),
+    #[error("no route matches the request")]
+    RouteNotFound,
+    #[error("invalid route: {0}")]
+    InvalidRoute(String),
+    #[error("invalid upload: {0}")]
+    InvalidUpload(String),
+    #[error("invalid module: {0}")]
+    InvalidModule(String),
+    #[error("invalid function response: {0}")]
+    InvalidGuestResponse(String),
+    #[error("function registry error: {0}")]
+    Registry(RegistryError),
+    /// The instances of the function could not be created
+    #[error("function unavailable: {0}")]
+    FunctionUnavailable(Arc<anyhow::Error>),
+    /// The guest failed while handling the request `request_id`
+    #[error("function execution failed: {error}")]
+    FunctionFailed {
+        request_id: String,
+        error: RuntimeError,
+    },
+    #[error("internal error: {0}")]
+    Internal(String),
+}
 
-    #[error("error while sending message to broadcaster")]
-    InvalidFunction,
-    #[error("error while sending message to broadcaster")]
-    FailFunctionExecution,
-}
\ No newline at end of file
+/// The status and the title reported when a function fails to start, or fails while
+/// handling a request
+fn function_failure(error: &RuntimeError) -> (StatusCode, &'static str) {
+    match error {
+        RuntimeError::PermissionDenied { .. } => (StatusCode::FORBIDDEN, "Host call not permitted"),
+        RuntimeError::UnsupportedImport { .. } => {
+            (StatusCode::NOT_IMPLEMENTED, "Function imports unsupported host functions")
+        }
+        RuntimeError::HostBindingNotFound(..) => {
+            (StatusCode::NOT_IMPLEMENTED, "Host binding not available")
+        }
+        RuntimeError::GuestCallTimeout { .. } | RuntimeError::InitializationFailedTimeout(_) => {
+            (StatusCode::GATEWAY_TIMEOUT, "Function timed out")
+        }
+        RuntimeError::FuelExhausted { .. } | RuntimeError::MemoryLimitExceeded(_) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "Function exceeded its resource limits")
+        }
+        RuntimeError::HostError(_) => (StatusCode::BAD_GATEWAY, "Host call failed"),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "Function failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wkr_core::errors::TrapInfo;
+
+    #[test]
+    fn function_failure_statuses() {
+        let timeout = RuntimeError::GuestCallTimeout {
+            operation: "handle".to_owned(),
+            trap: TrapInfo::default(),
+        };
+        assert_eq!(function_failure(&timeout).0, StatusCode::GATEWAY_TIMEOUT);
+        let fuel = RuntimeError::FuelExhausted { budget: 1, trap: TrapInfo::default() };
+        assert_eq!(function_failure(&fuel).0, StatusCode::SERVICE_UNAVAILABLE);
+        let trap = RuntimeError::Unreachable(TrapInfo::new("unreachable"));
+        assert_eq!(function_failure(&trap).0, StatusCode::INTERNAL_SERVER_ERROR);
+        let denied = RuntimeError::PermissionDenied {
+            binding: "fetch".to_owned(),
+            namespace: "fetch".to_owned(),
+            operation: "request".to_owned(),
+            reason: "host call not allowed".to_owned(),
+        };
+        assert_eq!(function_failure(&denied).0, StatusCode::FORBIDDEN);
+        let import = RuntimeError::UnsupportedImport {
+            module: "env".to_owned(),
+            name: "missing".to_owned(),
+        };
+        assert_eq!(function_failure(&import).0, StatusCode::NOT_IMPLEMENTED);
+    }
+}
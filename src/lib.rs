@@ -0,0 +1,8 @@
+mod access_log;
+mod error;
+mod sse;
+mod upload;
+pub mod registry;
+pub mod router;
+pub mod server;
+pub mod telemetry;
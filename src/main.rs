@@ -1,32 +1,231 @@
-// #[macro_use]
-// extern crate log;
-
-// mod resources;
-// mod server;
-// // mod guest;
-// mod pool;
-// // mod pool2;
-// mod host_pool;
-// mod bridge;
-// mod error;
-// mod testrunner;
-
-// use testrunner::test;
-// use server::serve;
-
-// pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-//     // serve().unwrap();
-//     test().unwrap();
-
-//     Ok(())
-// }
-use wkr_core::run;
-use tokio;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use wasmtest::server::{serve, ServerOptions, DEFAULT_ADDR};
+use wasmtest::telemetry;
+use wkr_core::{
+    compile_function, errors::Error as RuntimeError, inspect, module_cache_dir, Environment,
+    EnvironmentBuilder, FunctionConfig,
+};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // serve().unwrap();
-    run().await.unwrap();
+#[derive(Parser)]
+#[command(name = "wkr", version, about = "Run and serve waPC WebAssembly functions")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Instantiate a module and run its initialization code (`_start`, `wapc_init`)
+    Run(ModuleArgs),
+    /// Call an operation of a module and print its response
+    Invoke {
+        #[command(flatten)]
+        module: ModuleArgs,
+        /// The operation to call
+        operation: String,
+        /// File to read the payload from, `-` for stdin. The payload is empty by default.
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+        /// Encoding of the payload and of the response
+        #[arg(short, long, value_enum, default_value_t = Encoding::Msgpack)]
+        encoding: Encoding,
+    },
+    /// Compile modules ahead of time into the module cache
+    Compile {
+        #[arg(required = true)]
+        modules: Vec<PathBuf>,
+    },
+    /// Report what a module imports and exports
+    Inspect { module: PathBuf },
+    /// Start the HTTP server
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value_t = SocketAddr::from(DEFAULT_ADDR))]
+        addr: SocketAddr,
+        /// Include the messages and the backtraces of guest failures in error responses
+        #[arg(long, env = "WKR_DEBUG")]
+        debug: bool,
+    },
+}
+
+#[derive(Args)]
+struct ModuleArgs {
+    /// The wasm module. Its manifest (`module.toml` or `module.json`), if deployed next to
+    /// it, is completed by the options below.
+    module: PathBuf,
+    /// Environment variable exposed to the guest, as `KEY=VALUE`
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    env_vars: Vec<(String, String)>,
+    /// Directory the guest can access, as `DIR` or `GUEST_DIR=HOST_DIR`
+    #[arg(long = "dir", value_name = "DIR")]
+    dirs: Vec<String>,
+    /// Argument passed to the guest, after the module name
+    #[arg(long = "arg", value_name = "ARG")]
+    args: Vec<String>,
+    /// Fuel available to the initialization code and to the call
+    #[arg(long)]
+    fuel: Option<u64>,
+    /// Maximum size of the guest memory, in bytes
+    #[arg(long, value_name = "BYTES")]
+    memory_limit: Option<usize>,
+    /// Maximum duration of the initialization code and of the call, in milliseconds
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+    /// Link the imports the host doesn't provide to stubs that trap when called
+    #[arg(long)]
+    allow_unknown_imports: bool,
+}
+
+/// How the payload and the response of a call are encoded
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    /// The payload is read as JSON and sent as MessagePack, the response is printed as JSON
+    Msgpack,
+    /// The payload and the response are JSON documents, passed as is
+    Json,
+    /// The payload and the response are passed as is
+    Raw,
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", value))
+}
+
+impl ModuleArgs {
+    /// The configuration of the module: its manifest, if deployed next to it, overridden by
+    /// the command line options
+    async fn config(&self) -> anyhow::Result<FunctionConfig> {
+        let mut config = FunctionConfig::for_module(&self.module).await?;
+        config.argv.extend(self.args.iter().cloned());
+        config.env.extend(self.env_vars.iter().cloned());
+        for dir in &self.dirs {
+            let (guest, host) = dir.split_once('=').unwrap_or((dir, dir));
+            config.dirs.insert(guest.to_owned(), host.to_owned());
+        }
+        if self.fuel.is_some() {
+            config.limits.fuel = self.fuel;
+        }
+        if self.memory_limit.is_some() {
+            config.limits.memory = self.memory_limit;
+        }
+        if self.timeout.is_some() {
+            config.timeouts.init_ms = self.timeout;
+            config.timeouts.call_ms = self.timeout;
+        }
+        Ok(config)
+    }
+
+    async fn build(&self, module: &[u8]) -> anyhow::Result<Environment> {
+        let module_name = self
+            .module
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let builder = EnvironmentBuilder::new(module)
+            .cache_dir(module_cache_dir())
+            .allow_unknown_imports(self.allow_unknown_imports)
+            .inherit_stdio();
+        let environment = self.config().await?.apply(builder, &module_name).build()?;
+        Ok(environment)
+    }
+}
+
+fn read_input(input: Option<&Path>) -> std::io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    match input {
+        Some(path) if path == Path::new("-") => {
+            std::io::stdin().read_to_end(&mut payload)?;
+        }
+        Some(path) => payload = std::fs::read(path)?,
+        None => {}
+    }
+    Ok(payload)
+}
+
+fn encode_payload(payload: Vec<u8>, encoding: Encoding) -> anyhow::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Msgpack => {
+            let value: serde_json::Value = if payload.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::from_slice(&payload)?
+            };
+            wapc_codec::messagepack::serialize(&value).map_err(anyhow::Error::msg)
+        }
+        Encoding::Json | Encoding::Raw => Ok(payload),
+    }
+}
+
+fn print_response(response: &[u8], encoding: Encoding) -> anyhow::Result<()> {
+    match encoding {
+        Encoding::Msgpack => {
+            let value: serde_json::Value =
+                wapc_codec::messagepack::deserialize(response).map_err(anyhow::Error::msg)?;
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        }
+        Encoding::Json | Encoding::Raw => {
+            std::io::stdout().write_all(response)?;
+        }
+    }
+    Ok(())
+}
 
+async fn run(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run(args) => {
+            let module = tokio::fs::read(&args.module).await?;
+            let mut environment = args.build(&module).await?;
+            environment.init().await?;
+        }
+        Command::Invoke {
+            module: args,
+            operation,
+            input,
+            encoding,
+        } => {
+            let payload = encode_payload(read_input(input.as_deref())?, encoding)?;
+            let module = tokio::fs::read(&args.module).await?;
+            let mut environment = args.build(&module).await?;
+            let response = environment.call(&operation, &payload).await?;
+            print_response(&response, encoding)?;
+        }
+        Command::Compile { modules } => {
+            for path in &modules {
+                let artifact = compile_function(&path.to_string_lossy()).await?;
+                println!("{} -> {}", path.display(), artifact.display());
+            }
+        }
+        Command::Inspect { module } => {
+            let module = tokio::fs::read(module).await?;
+            let info = inspect(&module)?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Command::Serve { addr, debug } => serve(ServerOptions { addr, debug }).await?,
+    }
     Ok(())
 }
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = telemetry::init() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let result = run(cli.command).await;
+    telemetry::shutdown();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        if let Some(trap) = e.downcast_ref::<RuntimeError>().and_then(RuntimeError::trap) {
+            eprint!("{}", trap.format_backtrace());
+        }
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,484 @@
+use crate::router::Route;
+use axum::body::Bytes;
+use object_store::{local::LocalFileSystem, path::Path, ObjectStore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use wkr_core::FunctionConfig;
+
+const FUNCTIONS: &str = "functions";
+const VERSIONS: &str = "versions";
+const ALIASES: &str = "aliases";
+const MODULE: &str = "module.wasm";
+const METADATA: &str = "metadata.json";
+const ROUTES: &str = "routes.json";
+
+/// The directory the registry is stored in: `WKR_REGISTRY_DIR`, or `.wkr/registry`
+pub fn registry_dir() -> PathBuf {
+    std::env::var_os("WKR_REGISTRY_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(".wkr/registry"))
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("function not found: {0}")]
+    NotFound(String),
+    #[error("invalid name: `{0}`")]
+    InvalidName(String),
+    #[error("the module of {0} doesn't match its content hash")]
+    Corrupted(String),
+    #[error("invalid metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+    #[error(transparent)]
+    Store(#[from] object_store::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, RegistryError>;
+
+/// A published version of a function. Versions are immutable: publishing a function again
+/// creates a new version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionVersion {
+    pub name: String,
+    /// Sequence number of the version, starting at 1
+    pub version: u64,
+    /// SHA-256 of the module, hex encoded
+    pub sha256: String,
+    /// Size of the module, in bytes
+    pub size: usize,
+    /// Publication time, in seconds since the Unix epoch
+    pub created_at: u64,
+    pub config: FunctionConfig,
+}
+
+impl FunctionVersion {
+    /// Identifies the version across functions, e.g. `hello@3`
+    pub fn key(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+}
+
+/// What a [`FunctionRef`] points to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// The most recent version
+    Latest,
+    Version(u64),
+    Alias(String),
+}
+
+/// A reference to a version of a function: `hello`, for its most recent version, `hello@3`
+/// or `hello@prod`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRef {
+    pub name: String,
+    pub target: Target,
+}
+
+impl FromStr for FunctionRef {
+    type Err = RegistryError;
+
+    fn from_str(reference: &str) -> Result<Self> {
+        let (name, target) = match reference.split_once('@') {
+            Some((name, target)) => {
+                let target = match target.parse() {
+                    Ok(version) => Target::Version(version),
+                    Err(_) => Target::Alias(validate_name(target)?.to_owned()),
+                };
+                (name, target)
+            }
+            None => (reference, Target::Latest),
+        };
+        Ok(FunctionRef {
+            name: validate_name(name)?.to_owned(),
+            target,
+        })
+    }
+}
+
+/// Names of functions and aliases are made of ASCII letters, digits, `-`, `_` and `.`
+fn validate_name(name: &str) -> Result<&str> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(name)
+    } else {
+        Err(RegistryError::InvalidName(name.to_owned()))
+    }
+}
+
+/// Durable store of the published functions, their versions and their aliases, along
+/// with the route table of the server.
+///
+/// Each version keeps its module, along with its content hash and configuration, under
+/// `functions/<name>/versions/<version>/`. An alias, such as `prod` or `staging`, is a
+/// single object under `functions/<name>/aliases/` holding a version number: promoting a
+/// version replaces it atomically.
+///
+/// Version numbers are allocated by the registry, a store must not be shared by several
+/// servers publishing functions. The references resolved by the registry are kept in
+/// memory, and only forgotten when the registry itself publishes or promotes a version.
+pub struct Registry {
+    store: Arc<dyn ObjectStore>,
+    /// Serializes the allocation of version numbers
+    publishing: Mutex<()>,
+    /// The versions references were resolved to, by function name and target
+    resolved: RwLock<HashMap<(String, Target), FunctionVersion>>,
+}
+
+impl Registry {
+    /// A registry kept in `store`
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Registry {
+            store,
+            publishing: Mutex::new(()),
+            resolved: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Opens the registry stored in the local directory `dir`, creating it if needed
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Registry::new(Arc::new(LocalFileSystem::new_with_prefix(dir)?)))
+    }
+
+    /// Publishes `module` as a new version of the `name` function.
+    ///
+    /// Publishing the module and configuration of the most recent version again returns
+    /// that version instead of creating a new one.
+    pub async fn publish(
+        &self,
+        name: &str,
+        module: Bytes,
+        config: FunctionConfig,
+    ) -> Result<FunctionVersion> {
+        validate_name(name)?;
+        let sha256 = hex::encode(Sha256::digest(&module));
+
+        let _publishing = self.publishing.lock().await;
+        if let Some(current) = self.latest_version(name).await? {
+            if current.sha256 == sha256 && current.config == config {
+                return Ok(current);
+            }
+        }
+        // past the numbers of interrupted publications too, so their leftovers are ignored
+        let allocated = self.version_numbers(name).await?.last().copied();
+
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let version = FunctionVersion {
+            name: name.to_owned(),
+            version: allocated.unwrap_or_default() + 1,
+            sha256,
+            size: module.len(),
+            created_at,
+            config,
+        };
+
+        // the version exists once its metadata is written
+        let dir = version_path(name, version.version);
+        self.store.put(&dir.child(MODULE), module).await?;
+        let metadata = serde_json::to_vec_pretty(&version)?;
+        self.store.put(&dir.child(METADATA), metadata.into()).await?;
+        self.forget(name, Target::Latest);
+
+        tracing::info!(function = name, version = version.version, "function published");
+        Ok(version)
+    }
+
+    /// The metadata of a version
+    pub async fn version(&self, name: &str, version: u64) -> Result<FunctionVersion> {
+        validate_name(name)?;
+        let path = version_path(name, version).child(METADATA);
+        let metadata = self.get(&path, || format!("{}@{}", name, version)).await?;
+        Ok(serde_json::from_slice(&metadata)?)
+    }
+
+    /// The metadata of every version of a function, oldest first
+    pub async fn versions(&self, name: &str) -> Result<Vec<FunctionVersion>> {
+        let mut versions = Vec::new();
+        for version in self.version_numbers(name).await? {
+            match self.version(name, version).await {
+                Ok(version) => versions.push(version),
+                // a publication interrupted before its metadata was written
+                Err(RegistryError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(versions)
+    }
+
+    /// The aliases of a function and the versions they point to
+    pub async fn aliases(&self, name: &str) -> Result<BTreeMap<String, u64>> {
+        validate_name(name)?;
+        let (_, objects) = self.list(&function_path(name).child(ALIASES)).await?;
+
+        let mut aliases = BTreeMap::new();
+        for object in objects {
+            // skips the staging files of the aliases being promoted
+            if let Some(alias) = object.filename().filter(|alias| validate_name(alias).is_ok()) {
+                let version = self.alias(name, alias).await?;
+                aliases.insert(alias.to_owned(), version);
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Points `alias` to `version`. Requests resolving the alias switch from the previous
+    /// version to the new one at once.
+    pub async fn promote(&self, name: &str, alias: &str, version: u64) -> Result<FunctionVersion> {
+        validate_name(name)?;
+        if validate_name(alias)?.parse::<u64>().is_ok() {
+            return Err(RegistryError::InvalidName(alias.to_owned()));
+        }
+        let target = self.version(name, version).await?;
+
+        let path = function_path(name).child(ALIASES).child(alias);
+        self.store.put(&path, version.to_string().into()).await?;
+        self.forget(name, Target::Alias(alias.to_owned()));
+
+        tracing::info!(function = name, alias, version, "alias promoted");
+        Ok(target)
+    }
+
+    /// The version `reference` points to
+    pub async fn resolve(&self, reference: &FunctionRef) -> Result<FunctionVersion> {
+        let key = (reference.name.clone(), reference.target.clone());
+        if let Some(version) = self.resolved.read().unwrap().get(&key) {
+            return Ok(version.clone());
+        }
+        let version = self.resolve_stored(reference).await?;
+        self.resolved.write().unwrap().insert(key, version.clone());
+        Ok(version)
+    }
+
+    /// Forgets what `target` of the `name` function was resolved to
+    fn forget(&self, name: &str, target: Target) {
+        self.resolved.write().unwrap().remove(&(name.to_owned(), target));
+    }
+
+    /// The version `reference` points to, read from the store
+    async fn resolve_stored(&self, reference: &FunctionRef) -> Result<FunctionVersion> {
+        let version = match &reference.target {
+            Target::Latest => {
+                return self
+                    .latest_version(&reference.name)
+                    .await?
+                    .ok_or_else(|| RegistryError::NotFound(reference.name.clone()))
+            }
+            Target::Version(version) => *version,
+            Target::Alias(alias) => self.alias(&reference.name, alias).await?,
+        };
+        self.version(&reference.name, version).await
+    }
+
+    /// The module of a version, checked against its content hash
+    pub async fn module(&self, version: &FunctionVersion) -> Result<Bytes> {
+        let path = version_path(&version.name, version.version).child(MODULE);
+        let module = self.get(&path, || version.key()).await?;
+        if hex::encode(Sha256::digest(&module)) != version.sha256 {
+            return Err(RegistryError::Corrupted(version.key()));
+        }
+        Ok(module)
+    }
+
+    /// The route table of the server, empty until set
+    pub async fn routes(&self) -> Result<Vec<Route>> {
+        match self.get(&Path::from(ROUTES), || ROUTES.to_owned()).await {
+            Ok(routes) => Ok(serde_json::from_slice(&routes)?),
+            Err(RegistryError::NotFound(_)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the route table of the server
+    pub async fn set_routes(&self, routes: &[Route]) -> Result<()> {
+        let routes = serde_json::to_vec_pretty(routes)?;
+        self.store.put(&Path::from(ROUTES), routes.into()).await?;
+        Ok(())
+    }
+
+    async fn alias(&self, name: &str, alias: &str) -> Result<u64> {
+        let path = function_path(name).child(ALIASES).child(alias);
+        let version = self.get(&path, || format!("{}@{}", name, alias)).await?;
+        std::str::from_utf8(&version)
+            .ok()
+            .and_then(|version| version.trim().parse().ok())
+            .ok_or_else(|| RegistryError::Corrupted(format!("{}@{}", name, alias)))
+    }
+
+    /// The most recent version whose publication completed
+    async fn latest_version(&self, name: &str) -> Result<Option<FunctionVersion>> {
+        for version in self.version_numbers(name).await?.into_iter().rev() {
+            match self.version(name, version).await {
+                Ok(version) => return Ok(Some(version)),
+                // a publication interrupted before its metadata was written
+                Err(RegistryError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// The version numbers allocated to a function, in order
+    async fn version_numbers(&self, name: &str) -> Result<Vec<u64>> {
+        validate_name(name)?;
+        let (directories, _) = self.list(&function_path(name).child(VERSIONS)).await?;
+
+        let mut versions: Vec<u64> = directories
+            .iter()
+            .filter_map(|path| path.filename()?.parse().ok())
+            .collect();
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    /// Lists the directories and the objects right under `prefix`, none when it doesn't
+    /// exist
+    async fn list(&self, prefix: &Path) -> Result<(Vec<Path>, Vec<Path>)> {
+        match self.store.list_with_delimiter(Some(prefix)).await {
+            Ok(listing) => Ok((
+                listing.common_prefixes,
+                listing.objects.into_iter().map(|object| object.location).collect(),
+            )),
+            Err(object_store::Error::NotFound { .. }) => Ok((Vec::new(), Vec::new())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, path: &Path, reference: impl FnOnce() -> String) -> Result<Bytes> {
+        match self.store.get(path).await {
+            Ok(result) => Ok(result.bytes().await?),
+            Err(object_store::Error::NotFound { .. }) => Err(RegistryError::NotFound(reference())),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn function_path(name: &str) -> Path {
+    Path::from(FUNCTIONS).child(name)
+}
+
+fn version_path(name: &str, version: u64) -> Path {
+    function_path(name).child(VERSIONS).child(version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_references() {
+        let reference: FunctionRef = "hello".parse().unwrap();
+        assert_eq!(reference.name, "hello");
+        assert_eq!(reference.target, Target::Latest);
+
+        let reference: FunctionRef = "hello.wasm@3".parse().unwrap();
+        assert_eq!(reference.name, "hello.wasm");
+        assert_eq!(reference.target, Target::Version(3));
+
+        let reference: FunctionRef = "hello@prod".parse().unwrap();
+        assert_eq!(reference.target, Target::Alias("prod".to_owned()));
+
+        assert!("../hello".parse::<FunctionRef>().is_err());
+        assert!("hello@".parse::<FunctionRef>().is_err());
+        assert!("".parse::<FunctionRef>().is_err());
+    }
+
+    #[tokio::test]
+    async fn publish_and_promote() {
+        let dir = std::env::temp_dir().join(format!("wkr-registry-{}", uuid::Uuid::new_v4()));
+        let registry = Registry::open(&dir).unwrap();
+
+        let v1 = registry
+            .publish("hello", Bytes::from_static(b"v1"), FunctionConfig::default())
+            .await
+            .unwrap();
+        let v2 = registry
+            .publish("hello", Bytes::from_static(b"v2"), FunctionConfig::default())
+            .await
+            .unwrap();
+        assert_eq!((v1.version, v2.version), (1, 2));
+
+        // same module and configuration as the most recent version
+        let again = registry
+            .publish("hello", Bytes::from_static(b"v2"), FunctionConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(again, v2);
+
+        let latest = registry.resolve(&"hello".parse().unwrap()).await.unwrap();
+        assert_eq!(latest, v2);
+        assert_eq!(registry.module(&latest).await.unwrap(), Bytes::from_static(b"v2"));
+
+        registry.promote("hello", "prod", 1).await.unwrap();
+        let prod = registry.resolve(&"hello@prod".parse().unwrap()).await.unwrap();
+        assert_eq!(prod, v1);
+        assert_eq!(registry.aliases("hello").await.unwrap()["prod"], 1);
+
+        // resolutions are cached, and forgotten on promotions and publications
+        registry.promote("hello", "prod", 2).await.unwrap();
+        let prod = registry.resolve(&"hello@prod".parse().unwrap()).await.unwrap();
+        assert_eq!(prod, v2);
+        let v3 = registry
+            .publish("hello", Bytes::from_static(b"v3"), FunctionConfig::default())
+            .await
+            .unwrap();
+        let latest = registry.resolve(&"hello".parse().unwrap()).await.unwrap();
+        assert_eq!(latest, v3);
+
+        assert!(matches!(
+            registry.promote("hello", "prod", 4).await,
+            Err(RegistryError::NotFound(_))
+        ));
+        assert!(matches!(
+            registry.resolve(&"missing".parse().unwrap()).await,
+            Err(RegistryError::NotFound(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_publication() {
+        let dir = std::env::temp_dir().join(format!("wkr-registry-{}", uuid::Uuid::new_v4()));
+        let registry = Registry::open(&dir).unwrap();
+
+        let v1 = registry
+            .publish("hello", Bytes::from_static(b"v1"), FunctionConfig::default())
+            .await
+            .unwrap();
+        // the module of version 2 was written, not its metadata
+        let module = version_path("hello", 2).child(MODULE);
+        registry.store.put(&module, Bytes::from_static(b"v2")).await.unwrap();
+
+        let latest = registry.resolve(&"hello".parse().unwrap()).await.unwrap();
+        assert_eq!(latest, v1);
+        assert_eq!(registry.versions("hello").await.unwrap(), vec![v1]);
+
+        let v3 = registry
+            .publish("hello", Bytes::from_static(b"v3"), FunctionConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(v3.version, 3);
+        let latest = registry.resolve(&"hello".parse().unwrap()).await.unwrap();
+        assert_eq!(latest, v3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
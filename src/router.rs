@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+use urlpattern::{UrlPattern, UrlPatternInit, UrlPatternMatchInput};
+
+#[derive(Error, Debug)]
+pub enum RouteError {
+    #[error("invalid pattern in route {index}: {message}")]
+    InvalidPattern { index: usize, message: String },
+}
+
+/// Maps the requests matching a host, a path and a method to the operation of a function
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    /// Host pattern, such as `api.example.com` or `*.example.com`. Any host when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Path pattern, such as `/users/:id` or `/static/*`
+    pub path: String,
+    /// Methods the route accepts, any of them when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// The function handling the requests: `hello`, `hello@3` or `hello@prod`
+    pub function: String,
+    /// The operation of the function called for each request
+    pub operation: String,
+}
+
+/// The route a request matched, with the parameters captured by its patterns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    pub function: String,
+    pub operation: String,
+    /// Named groups and wildcards of the path pattern, and of the host pattern when the
+    /// route has one. The path ones take precedence.
+    pub params: HashMap<String, String>,
+}
+
+struct CompiledRoute {
+    route: Route,
+    pattern: UrlPattern,
+}
+
+/// An ordered list of [`Route`]s, the first route matching a request handles it
+#[derive(Default)]
+pub struct RouteTable {
+    routes: Vec<CompiledRoute>,
+}
+
+impl RouteTable {
+    /// Compiles the patterns of `routes`, failing on the first invalid one
+    pub fn new(routes: Vec<Route>) -> Result<Self, RouteError> {
+        let routes = routes
+            .into_iter()
+            .enumerate()
+            .map(|(index, route)| {
+                let init = UrlPatternInit {
+                    hostname: Some(route.host.clone().unwrap_or_else(|| "*".to_owned())),
+                    pathname: Some(route.path.clone()),
+                    ..Default::default()
+                };
+                let pattern = <UrlPattern>::parse(init).map_err(|e| RouteError::InvalidPattern {
+                    index,
+                    message: e.to_string(),
+                })?;
+                Ok(CompiledRoute { route, pattern })
+            })
+            .collect::<Result<_, RouteError>>()?;
+        Ok(RouteTable { routes })
+    }
+
+    /// The routes of the table, in order
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.iter().map(|compiled| compiled.route.clone()).collect()
+    }
+
+    /// Finds the route of a request to `host` and `path`, the host without its port
+    pub fn find(&self, method: &str, host: &str, path: &str) -> Option<RouteMatch> {
+        let input = UrlPatternInit {
+            hostname: Some(host.to_owned()),
+            pathname: Some(path.to_owned()),
+            ..Default::default()
+        };
+
+        self.routes.iter().find_map(|compiled| {
+            let route = &compiled.route;
+            if !route.methods.is_empty()
+                && !route.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            {
+                return None;
+            }
+            let result = compiled
+                .pattern
+                .exec(UrlPatternMatchInput::Init(input.clone()))
+                .ok()??;
+
+            let mut params = HashMap::new();
+            if route.host.is_some() {
+                params.extend(result.hostname.groups);
+            }
+            params.extend(result.pathname.groups);
+            Some(RouteMatch {
+                function: route.function.clone(),
+                operation: route.operation.clone(),
+                params,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: Option<&str>, path: &str, methods: &[&str], function: &str) -> Route {
+        Route {
+            host: host.map(str::to_owned),
+            path: path.to_owned(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            function: function.to_owned(),
+            operation: "handle".to_owned(),
+        }
+    }
+
+    #[test]
+    fn find_routes() {
+        let table = RouteTable::new(vec![
+            route(Some(":tenant.example.com"), "/users/:id", &["GET"], "users"),
+            route(None, "/users/:id", &[], "users@prod"),
+            route(None, "/static/*", &[], "assets"),
+        ])
+        .unwrap();
+
+        let found = table.find("GET", "acme.example.com", "/users/42").unwrap();
+        assert_eq!(found.function, "users");
+        assert_eq!(found.params["tenant"], "acme");
+        assert_eq!(found.params["id"], "42");
+
+        // the first route only accepts GET requests
+        let found = table.find("DELETE", "acme.example.com", "/users/42").unwrap();
+        assert_eq!(found.function, "users@prod");
+
+        let found = table.find("GET", "localhost", "/static/css/site.css").unwrap();
+        assert_eq!(found.function, "assets");
+        assert_eq!(found.params["0"], "css/site.css");
+
+        assert!(table.find("GET", "localhost", "/orders").is_none());
+    }
+
+    #[test]
+    fn reject_invalid_patterns() {
+        let routes = vec![route(None, "/users/(", &[], "users")];
+        assert!(matches!(
+            RouteTable::new(routes),
+            Err(RouteError::InvalidPattern { index: 0, .. })
+        ));
+    }
+}
@@ -1,33 +1,47 @@
-mod error;
-mod sse;
-mod utils;
-
 use axum::{
-    body::{ Bytes, Full},
-    extract::{Query, State, Path},
+    body::{Body, Bytes, Full, HttpBody, StreamBody},
+    extract::{DefaultBodyLimit, FromRequest, Query, State, Path},
+    middleware,
     headers::{authorization::{Bearer, Credentials}, HeaderName},
-    http::{HeaderMap, StatusCode, header::AUTHORIZATION, Method, Uri, HeaderValue},
+    http::{HeaderMap, StatusCode, header::{AUTHORIZATION, CONTENT_TYPE, HOST}, uri::Authority, Request, HeaderValue},
     response::{sse::{ Sse}, Response },
     response::{Html, IntoResponse},
-    routing::{any, get, post},
-    Router,
+    routing::{get, post, put},
+    Json, Router,
 };
-use error::{ UserRepoError};
+use crate::error::FunctionError;
 use serde::{Deserialize, Serialize};
-use sse::{Broadcaster, ClientStream};
-use utils::get_wasm_file_function;
-use wkr_core::{create_function_engine_with_bytes};
-use std::sync::Arc;
-use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use crate::sse::{self, Broadcaster, ClientStream};
+use crate::registry::{registry_dir, FunctionRef, FunctionVersion, Registry};
+use crate::upload::{read_upload, upload_dirs, validate_module, MAX_UPLOAD_SIZE};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use wkr_core::{create_function_engine_with_bytes, errors::{Error as RuntimeError, TrapInfo}, stats, host_pool::{HostPool, HostPoolBuilder, PooledEnvironment}, FunctionConfig};
+use crate::router::{Route, RouteTable};
+use std::sync::{Arc, RwLock};
+use std::{collections::{BTreeMap, HashMap}, io, net::SocketAddr, time::Duration};
+use futures::StreamExt;
+use tracing::Instrument;
 use moka::future::Cache;
+use serde_bytes::ByteBuf;
+use crate::access_log::{access_log, RequestContext};
+use crate::telemetry::trace_request;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use wkr_fetch::{add_readable_body, add_streamed_response, BODY_CHANNEL_CAPACITY};
 use crate::error::AppError;
 use wapc_codec::messagepack::{deserialize, serialize};
 #[derive(Clone)]
 struct AppState {
     #[allow(unused)]
     functions: HashMap<String, Vec<u8>>,
-    cache: Cache<String, Vec<u8>> ,
+    registry: Arc<Registry>,
+    /// Warm instances of the function versions, by [`FunctionVersion::key`]
+    pools: Cache<String, HostPool>,
+    /// Routes of the requests served by functions, replaced as a whole on updates
+    routes: Arc<RwLock<Arc<RouteTable>>>,
     broadcaster: Arc<std::sync::Mutex<Broadcaster>>,
+    /// Renders the metrics recorded by the server and the runtime
+    metrics: PrometheusHandle,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,8 +49,26 @@ struct AppState {
 pub struct GuestRequest {
     method: String,
     url: String,
-    headers: Vec<(String, String)>,
+    /// The headers in order, duplicates included, their values as bytes
+    headers: Vec<(String, ByteBuf)>,
     body: Vec<u8>,
+    /// Parameters captured by the route of the request
+    #[serde(default)]
+    params: HashMap<String, String>,
+    /// The id of the request, from its `x-request-id` header or generated
+    #[serde(default)]
+    request_id: String,
+    /// The body of the request, read with `fetch:read_body`, when the function streams it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_rid: Option<u32>,
+    /// Where the function sends the status and the headers of its response with
+    /// `fetch:respond`, before writing its body, when it streams it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_head_rid: Option<u32>,
+    /// The body of the response, written with `fetch:write_body` and ended with
+    /// `fetch:close`, when the function streams it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_body_rid: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -44,34 +76,59 @@ pub struct GuestRequest {
 pub struct GuestResponse {
     status: u16,
     url: String,
-    headers: Vec<(String, String)>,
+    /// The headers in order, each of them sent even when a name repeats
+    headers: Vec<(String, ByteBuf)>,
     body: Vec<u8>,
 }
 
-#[tokio::main]
-async fn main() {
-    // initialize tracing
-    tracing_subscriber::fmt::init();
+/// How long a call of a function without call timeout can take
+const DEFAULT_CALL_DEADLINE: Duration = Duration::from_secs(5 * 60);
+
+/// The address the server listens on by default
+pub const DEFAULT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3333);
+
+/// Settings of the HTTP server
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// The address to listen on
+    pub addr: SocketAddr,
+    /// Includes the messages and the backtraces of guest failures in error responses
+    pub debug: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            addr: SocketAddr::from(DEFAULT_ADDR),
+            debug: false,
+        }
+    }
+}
+
+/// Starts the HTTP server and serves requests until it fails
+pub async fn serve(options: ServerOptions) -> anyhow::Result<()> {
+    let ServerOptions { addr, debug } = options;
+    crate::error::set_debug(debug);
     sse::print_jwt();
 
-    let cache:Cache<String, Vec<u8>> = Cache::builder()
-        // .max_size(10_000)
-        // This cache will hold up to 100MiB of values.
-        .max_capacity(100 * 1024 * 1024)
-        // Time to live (TTL): 30 minutes
-        .time_to_live(Duration::from_secs(30 * 60))
-        // Time to idle (TTI):  5 minutes
+    let registry = Arc::new(Registry::open(registry_dir())?);
+    let routes = RouteTable::new(registry.routes().await?)?;
+
+    // Warm instances of the published functions, dropped along with their pool once idle.
+    // Versions are immutable, so entries never need to be invalidated.
+    let pools: Cache<String, HostPool> = Cache::builder()
+        .max_capacity(1_000)
         .time_to_idle(Duration::from_secs( 5 * 60))
-        // Create the cache.
         .build();
-    // Create a cache that can store up to 10,000 entries.
-    // let cache:Cache<String, Vec<u8>> = Cache::new(10_000);
     let broadcaster = Broadcaster::create();
     let functions: HashMap<String, Vec<u8>> = HashMap::new();
     let shared_state = Arc::new(AppState {
         functions,
         broadcaster,
-        cache
+        registry,
+        pools,
+        routes: Arc::new(RwLock::new(Arc::new(routes))),
+        metrics: install_metrics_recorder()?,
     });
     
 
@@ -82,21 +139,30 @@ async fn main() {
         .route("/sse", get(sse_handler))
         .route("/sse_publish", post(sse_publish_handler))
         // `POST /users` goes to `create_user`
-        .route("/metric", any(metric_handler))
-        .route("/add/:function", post(cache_function_handler))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            "/functions/:function",
+            get(function_handler)
+                .post(upload_function_handler)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+        )
+        .route("/functions/:function/aliases/:alias", put(promote_handler))
         .route("/invoke/:function/:event", post(invoke_function_handler))
+        .route("/routes", get(routes_handler).put(update_routes_handler))
+        // requests to any other path are routed to functions by the route table
+        .fallback(route_request_handler)
+        .layer(middleware::from_fn(trace_request))
+        .layer(middleware::from_fn(access_log))
         .with_state(shared_state);
 
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3333));
     tracing::debug!("listening on {}", addr);
     let server = axum::Server::bind(&addr).serve(app.into_make_service());
 
     println!("Listening on http://{}", addr);
-    if let Err(err) = server.await {
-        eprintln!("Server error: {}", err);
-    }
+    server.await?;
+    Ok(())
 }
 
 // basic handler that responds with a static string
@@ -104,82 +170,399 @@ async fn root() -> Html<&'static str> {    // "Hello, World!"
 
     Html(sse::HTML)
 }
-async fn cache_function_handler(
+/// Publishes an uploaded module as a new version of the function, once validated
+async fn upload_function_handler(
     State(state): State<Arc<AppState>>,
-    Path(params): Path<HashMap<String, String>>,
+    Path(function): Path<String>,
+    request: Request<Body>,
 ) -> Result<impl IntoResponse, AppError> {
-    let function = params.get("function").ok_or(UserRepoError::NotFound)?;
-    // let event = params.get("event").ok_or(UserRepoError::NotFound)?;
+    let upload = read_upload(request, &state).await?;
+    validate_module(&function, upload.module.clone(), &upload.config, &upload_dirs()).await?;
+
+    let version = state.registry
+        .publish(&function, upload.module, upload.config)
+        .await
+        .map_err(FunctionError::Registry)?;
+
+    Ok((StatusCode::CREATED, Json(version)))
+}
+
+#[derive(Serialize)]
+struct FunctionListing {
+    versions: Vec<FunctionVersion>,
+    aliases: BTreeMap<String, u64>,
+}
 
-    let cache =&state.cache;
-    let wasm = get_wasm_file_function(function.to_string()).await.map_err(|_e| UserRepoError::InvalidFunction)?;
+/// Lists the versions and the aliases of a function
+async fn function_handler(
+    State(state): State<Arc<AppState>>,
+    Path(function): Path<String>,
+) -> Result<Json<FunctionListing>, AppError> {
+    let versions = state.registry.versions(&function).await.map_err(FunctionError::Registry)?;
+    if versions.is_empty() {
+        return Err(FunctionError::NotFound.into());
+    }
+    let aliases = state.registry.aliases(&function).await.map_err(FunctionError::Registry)?;
+
+    Ok(Json(FunctionListing { versions, aliases }))
+}
 
-    let _function = cache.insert(function.to_string(), wasm).await;
+#[derive(Deserialize)]
+struct Promotion {
+    version: u64,
+}
 
-    Ok(StatusCode::OK.into_response())
+/// Points an alias of a function, such as `prod`, to one of its versions
+async fn promote_handler(
+    State(state): State<Arc<AppState>>,
+    Path((function, alias)): Path<(String, String)>,
+    Json(promotion): Json<Promotion>,
+) -> Result<Json<FunctionVersion>, AppError> {
+    let version = state.registry
+        .promote(&function, &alias, promotion.version)
+        .await
+        .map_err(FunctionError::Registry)?;
+
+    Ok(Json(version))
 }
 
 async fn invoke_function_handler(
     State(state): State<Arc<AppState>>,
     Path(params): Path<HashMap<String, String>>,
-    method: Method,
-    uri: Uri,
-    headers_map: HeaderMap, 
     // Query(params): Query<HashMap<String, String>>,        
-    body: Bytes
+    request: Request<Body>,
 ) -> Result<impl IntoResponse, AppError> {
-    let function = params.get("function").ok_or(UserRepoError::NotFound)?;
-    let event = params.get("event").ok_or(UserRepoError::NotFound)?;
+    let function = params.get("function").ok_or(FunctionError::NotFound)?;
+    let event = params.get("event").ok_or(FunctionError::NotFound)?;
 
-    let cache =&state.cache;
-    let function = cache.get(function).ok_or(UserRepoError::InvalidFunction)?;
+    call_function(&state, function, event, HashMap::new(), request).await
+}
 
-    let method = method.to_string();
-    let url = uri.to_string();
+/// Hands a request to the function its route points to
+async fn route_request_handler(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+) -> Result<impl IntoResponse, AppError> {
+    let host = request_host(&request);
+    let routes = state.routes.read().map_err(|_e| route_table_poisoned())?.clone();
+    let route = routes
+        .find(request.method().as_str(), &host, request.uri().path())
+        .ok_or(FunctionError::RouteNotFound)?;
 
-    let mut headers: Vec<(String, String)> = Vec::new();
-    for (key, val) in headers_map.iter() {
-        headers.push((key.to_string(), val.to_str().unwrap().to_string()));
-    }
+    call_function(&state, &route.function, &route.operation, route.params, request).await
+}
+
+/// The host a request is sent to, without its port, from its `Host` header or its URI.
+/// IPv6 addresses keep their brackets, as in `[::1]`.
+fn request_host<B>(request: &Request<B>) -> String {
+    request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map(|authority| authority.host().to_owned())
+        .or_else(|| request.uri().host().map(str::to_owned))
+        .unwrap_or_default()
+}
 
-    let request_args = GuestRequest {
+/// Calls `operation` of `function` with a request, `function` being `name`, `name@version`
+/// or `name@alias`
+async fn call_function(
+    state: &AppState,
+    function: &str,
+    event: &str,
+    params: HashMap<String, String>,
+    request: Request<Body>,
+) -> Result<Response, AppError> {
+    let reference: FunctionRef = function.parse().map_err(FunctionError::Registry)?;
+    let version = state.registry.resolve(&reference).await.map_err(FunctionError::Registry)?;
+    let context = RequestContext::of(&request);
+    context.set_function(&version.name, version.version);
+    let request_id = context.id.clone();
+
+    let method = request.method().to_string();
+    let url = request.uri().to_string();
+
+    let headers = guest_headers(request.headers());
+
+    let mut request_args = GuestRequest {
         method,
         url,
         headers,
-        body: body.to_vec()
+        body: Vec::new(),
+        params,
+        request_id: request_id.clone(),
+        body_rid: None,
+        response_head_rid: None,
+        response_body_rid: None,
     };
 
-    let resp = serialize(&request_args).map_err(|_e| UserRepoError::FailFunctionExecution)?;
+    let pool = state.pools
+        .try_get_with(version.key(), async {
+            let module = state.registry.module(&version).await?;
+            let mut template = create_function_engine_with_bytes(&version.name, module.to_vec(), &version.config).await?;
+            template.set_function_version(version.version.to_string());
+            Ok::<_, anyhow::Error>(HostPoolBuilder::new().build(template).await?)
+        })
+        .await
+        .map_err(FunctionError::FunctionUnavailable)?;
+    let failed = |error| FunctionError::FunctionFailed { request_id: request_id.clone(), error };
+    let deadline = call_deadline(&version.config);
+
+    if version.config.streaming {
+        let mut environment = pool.get().await.map_err(failed)?;
+        environment.set_request_id(request_id.clone());
+        let body = request.into_body();
+        return call_streaming(environment, event, deadline, context, request_args, body).await;
+    }
 
-    let mut environment = create_function_engine_with_bytes(function).await.map_err(|_e| UserRepoError::FailFunctionExecution)?;
-    environment.init().await.map_err(|_e| UserRepoError::FailFunctionExecution)?;
-    let guest_result = environment.call(&event, &resp).await.map_err(|_e| UserRepoError::FailFunctionExecution)?;
+    // the body is read before taking an instance, so slow clients don't hold instances
+    let body = match Bytes::from_request(request, state).await {
+        Ok(body) => body,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    context.add_bytes_in(body.len());
+    request_args.body = body.to_vec();
 
-    let mut guest_response: GuestResponse = deserialize(&guest_result).map_err(|_e| UserRepoError::FailFunctionExecution)?;
+    let mut environment = pool.get().await.map_err(failed)?;
+    environment.set_request_id(request_id.clone());
+    let resp = serialize(&request_args).map_err(|e| FunctionError::Internal(e.to_string()))?;
+    let guest_result = invoke(environment, event, &resp, deadline).await.map_err(failed)?;
 
-    let mut response = Response::builder().status(guest_response.status);
-    let headers = response.headers_mut().ok_or( UserRepoError::FailFunctionExecution)?;
-    
-    //loop over guest_response.headers
-    while let Some((key, val)) = guest_response.headers.pop() {
-        let key = HeaderName::from_bytes(key.as_bytes()).map_err(|_e| UserRepoError::FailFunctionExecution)?;
-        let val = HeaderValue::from_str(&val).map_err(|_e| UserRepoError::FailFunctionExecution)?;
-        headers.insert(key, val);
-    };     
-    
-   let response = response
-    .body(Full::from(guest_response.body))
-    .map_err(|_e| UserRepoError::FailFunctionExecution)?;
+    Ok(guest_response(&guest_result)?)
+}
+
+/// How long a call can take, waiting on the host included: the call timeout of the
+/// function, or [`DEFAULT_CALL_DEADLINE`]
+fn call_deadline(config: &FunctionConfig) -> Duration {
+    config
+        .timeouts
+        .call_ms
+        .map_or(DEFAULT_CALL_DEADLINE, Duration::from_millis)
+}
+
+/// Calls `event` on an instance of a pool, which is dropped if the call fails since the
+/// guest may have been left in an inconsistent state.
+///
+/// The epoch deadlines of the guest don't expire while it waits on a host call, such as
+/// reading a body, so the call is also given a wall-clock `deadline`.
+async fn invoke(
+    mut environment: PooledEnvironment,
+    event: &str,
+    payload: &[u8],
+    deadline: Duration,
+) -> Result<Vec<u8>, RuntimeError> {
+    let result = tokio::time::timeout(deadline, environment.invoke(event, payload))
+        .await
+        .unwrap_or_else(|_elapsed| {
+            Err(RuntimeError::GuestCallTimeout {
+                operation: event.to_owned(),
+                trap: TrapInfo::new(format!("call deadline of {:?} exceeded", deadline)),
+            })
+        });
+    if result.is_err() {
+        environment.discard();
+    }
+    // the output and the logs of the guest were already emitted by the runtime as events of
+    // the invocation span, tagged with the request id
+    result.map(|result| result.response)
+}
+
+/// Builds the response of a function from the [`GuestResponse`] it returned
+fn guest_response(guest_result: &[u8]) -> Result<Response, FunctionError> {
+    let guest_response: GuestResponse = deserialize(guest_result)
+        .map_err(|e| FunctionError::InvalidGuestResponse(e.to_string()))?;
+    let response = response_head(guest_response.status, guest_response.headers)?
+        .body(Full::from(guest_response.body))
+        .map_err(|e| FunctionError::Internal(e.to_string()))?;
 
     Ok(response.into_response())
+}
 
+/// Calls a function streaming its bodies. The guest reads the body of the request as it
+/// arrives, and the response is sent as soon as the guest hands over its head, its body
+/// being chunked as the guest writes it. A guest returning without sending a head responds
+/// with its [`GuestResponse`], as when not streaming.
+async fn call_streaming(
+    environment: PooledEnvironment,
+    event: &str,
+    deadline: Duration,
+    context: Arc<RequestContext>,
+    mut request_args: GuestRequest,
+    body: Body,
+) -> Result<Response, AppError> {
+    let request_id = context.id.clone();
+    // the resources are dropped along with the others of the guest once the call is over,
+    // which ends the body of the response if the guest didn't
+    let (request_body, response) = {
+        let resource_table = environment.resource_table();
+        let mut resource_table = resource_table.lock().await;
+
+        let (request_body, request_body_rx) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        request_args.body_rid = Some(add_readable_body(&mut resource_table, request_body_rx));
+        // the guest can't write the body before sending the head, nothing reads it before
+        let response = add_streamed_response(&mut resource_table);
+        request_args.response_head_rid = Some(response.head_rid);
+        request_args.response_body_rid = Some(response.body_rid);
+
+        (request_body, response)
+    };
+    tokio::spawn(forward_body(body, request_body, context));
+
+    let resp = serialize(&request_args).map_err(|e| FunctionError::Internal(e.to_string()))?;
+    let event = event.to_owned();
+    // the call outlives the handler, it stays in the span of the request
+    let span = tracing::Span::current();
+    let call = tokio::spawn(
+        async move { invoke(environment, &event, &resp, deadline).await }.instrument(span),
+    );
+
+    let head = match response.head.await {
+        Ok(head) => head,
+        Err(_) => {
+            // the guest is done and returned its whole response
+            let guest_result = call
+                .await
+                .map_err(|e| FunctionError::Internal(e.to_string()))?
+                .map_err(|error| FunctionError::FunctionFailed { request_id, error })?;
+            return Ok(guest_response(&guest_result)?);
+        }
+    };
+
+    // the status is already sent when the guest fails, the body is aborted instead
+    let outcome = futures::stream::once(call).filter_map(move |result| {
+        let request_id = request_id.clone();
+        async move {
+            let error = match result {
+                Ok(Ok(_)) => return None,
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            tracing::error!(request_id = %request_id, "streamed function failed: {}", error);
+            Some(Err(io::Error::new(io::ErrorKind::Other, error)))
+        }
+    });
+    // empty chunks are the end of body marker of the fetch body resources
+    let chunks = ReceiverStream::new(response.body)
+        .filter(|chunk| futures::future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())))
+        .chain(outcome);
 
+    let response = response_head(head.status, head.headers)?
+        .body(StreamBody::new(chunks))
+        .map_err(|e| FunctionError::Internal(e.to_string()))?;
+
+    Ok(response.into_response())
+}
+
+/// Forwards the body of a request to the guest reading it, as long as the guest holds it.
+/// The body ends with an empty chunk, as the bodies of `fetch` responses do.
+async fn forward_body(
+    mut body: Body,
+    request_body: mpsc::Sender<io::Result<Bytes>>,
+    context: Arc<RequestContext>,
+) {
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) if chunk.is_empty() => continue,
+            Ok(chunk) => {
+                context.add_bytes_in(chunk.len());
+                Ok(chunk)
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+        let failed = chunk.is_err();
+        if request_body.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+    let _ = request_body.send(Ok(Bytes::new())).await;
+}
+
+/// The headers of a request as handed to a guest: in order, duplicates included, their
+/// values as bytes since they need not be UTF-8
+fn guest_headers(headers: &HeaderMap) -> Vec<(String, ByteBuf)> {
+    headers
+        .iter()
+        .map(|(key, val)| (key.to_string(), ByteBuf::from(val.as_bytes())))
+        .collect()
+}
+
+/// Starts the response of a guest with its status and headers
+fn response_head(
+    status: u16,
+    guest_headers: Vec<(String, ByteBuf)>,
+) -> Result<axum::http::response::Builder, FunctionError> {
+    let status = StatusCode::from_u16(status)
+        .map_err(|_e| FunctionError::InvalidGuestResponse(format!("invalid status {}", status)))?;
+    let mut response = Response::builder().status(status);
+    let headers = response
+        .headers_mut()
+        .ok_or_else(|| FunctionError::Internal("invalid response".to_owned()))?;
+
+    for (key, val) in guest_headers {
+        let name = HeaderName::from_bytes(key.as_bytes()).map_err(|_e| {
+            FunctionError::InvalidGuestResponse(format!("invalid header name `{}`", key))
+        })?;
+        let val = HeaderValue::from_bytes(&val).map_err(|_e| {
+            FunctionError::InvalidGuestResponse(format!("invalid value for header `{}`", key))
+        })?;
+        // repeated headers, such as `set-cookie`, are all sent
+        headers.append(name, val);
+    }
+
+    Ok(response)
 }
 
 
 
-async fn metric_handler(State(state): State<Arc<AppState>>) {
-    // ...
+/// Lists the routes of the requests served by functions
+async fn routes_handler(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Route>>, AppError> {
+    let routes = state.routes.read().map_err(|_e| route_table_poisoned())?.routes();
+    Ok(Json(routes))
+}
+
+/// Replaces the route table. The new routes apply to the next requests, once all of them
+/// are validated and saved.
+async fn update_routes_handler(
+    State(state): State<Arc<AppState>>,
+    Json(routes): Json<Vec<Route>>,
+) -> Result<Json<Vec<Route>>, AppError> {
+    for route in &routes {
+        route.function.parse::<FunctionRef>().map_err(FunctionError::Registry)?;
+    }
+    let table = RouteTable::new(routes.clone()).map_err(|e| FunctionError::InvalidRoute(e.to_string()))?;
+    state.registry.set_routes(&routes).await.map_err(FunctionError::Registry)?;
+    *state.routes.write().map_err(|_e| route_table_poisoned())? = Arc::new(table);
+
+    Ok(Json(routes))
+}
+
+fn route_table_poisoned() -> FunctionError {
+    FunctionError::Internal("the route table lock is poisoned".to_owned())
+}
+
+/// Buckets of the histograms in seconds, from a fast host call to a slow compilation
+const SECONDS_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// Installs the Prometheus recorder of the metrics of the process, which can only be done
+/// once
+fn install_metrics_recorder() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), &SECONDS_BUCKETS)?
+        .install_recorder()?;
+    stats::describe();
+    sse::describe_metrics();
+    Ok(handle)
+}
+
+/// Renders the metrics in the Prometheus text format
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
 }
 
 async fn sse_publish_handler(
@@ -236,3 +619,50 @@ fn get_bearer_token(headers: HeaderMap) -> Option<Bearer> {
     };
     bearer
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_as_bytes() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.append("x-raw", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+
+        let guest = guest_headers(&headers);
+        assert_eq!(guest.len(), 3);
+        assert_eq!(guest[2], ("x-raw".to_owned(), ByteBuf::from(b"caf\xe9".to_vec())));
+
+        // values go over the wire as msgpack bin, not str
+        let wire = serialize(&guest[2..]).unwrap();
+        assert_eq!(wire, b"\x91\x92\xa5x-raw\xc4\x04caf\xe9");
+        let guest: Vec<(String, ByteBuf)> = deserialize(&wire).unwrap();
+
+        let response = response_head(200, guest).unwrap().body(()).unwrap();
+        assert_eq!(response.headers()["x-raw"].as_bytes(), b"caf\xe9");
+
+        let response = response_head(200, guest_headers(&headers)).unwrap().body(()).unwrap();
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+
+        let invalid = vec![("x-bad".to_owned(), ByteBuf::from(b"a\nb".to_vec()))];
+        assert!(response_head(200, invalid).is_err());
+    }
+
+    #[test]
+    fn request_hosts() {
+        let host = |host: &str| {
+            let request = Request::builder().header(HOST, host).body(()).unwrap();
+            request_host(&request)
+        };
+        assert_eq!(host("api.example.com"), "api.example.com");
+        assert_eq!(host("api.example.com:8080"), "api.example.com");
+        assert_eq!(host("[::1]"), "[::1]");
+        assert_eq!(host("[::1]:8080"), "[::1]");
+
+        let request = Request::builder().uri("http://localhost:3333/").body(()).unwrap();
+        assert_eq!(request_host(&request), "localhost");
+    }
+}
@@ -23,6 +23,18 @@ use futures::Stream;
 use std::sync::Mutex;
 
 use crate::error::Error;
+use metrics::{describe_counter, describe_gauge, gauge, increment_counter};
+
+/// Clients subscribed to the hub
+pub const SUBSCRIBERS: &str = "wkr_sse_subscribers";
+/// Updates dispatched by the hub, by `private`
+pub const PUBLICATIONS: &str = "wkr_sse_publications_total";
+
+/// Registers the descriptions of the hub metrics with the installed recorder
+pub fn describe_metrics() {
+    describe_gauge!(SUBSCRIBERS, "Clients subscribed to the hub");
+    describe_counter!(PUBLICATIONS, "Updates dispatched by the hub");
+}
 
 pub const HTML: &str = r#"
 <!DOCTYPE html>
@@ -313,6 +325,7 @@ impl Broadcaster {
             }
         }
         self.subscribers = ok_subscribers;
+        gauge!(SUBSCRIBERS, self.subscribers.len() as f64);
     }
 
     fn new_client(
@@ -334,6 +347,7 @@ impl Broadcaster {
             connection: tx,
         };
         self.subscribers.push(subscriber);
+        gauge!(SUBSCRIBERS, self.subscribers.len() as f64);
 
         //return a stream receiver
         Ok(ClientStream(rx))
@@ -342,6 +356,7 @@ impl Broadcaster {
     fn send(&self, publication: Publication)-> Result<()> {
         let candidates = publication.topic.clone();
         let msg = encode_msg(publication.clone());
+        increment_counter!(PUBLICATIONS, "private" => publication.private.to_string());
 
         // Dispatch message to subscribers
         // self.subscribers.iter().for_each(|subscriber| {
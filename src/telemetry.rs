@@ -0,0 +1,106 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use std::fs::File;
+use tracing::{field, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::access_log::RequestContext;
+
+/// File the spans are written to when set, and no OTLP endpoint is
+pub const TRACE_FILE_ENV: &str = "WKR_TRACE_FILE";
+
+/// Endpoint of the OTLP collector the spans are exported to, over gRPC
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Installs the logs and the tracing of the process. Logs are printed to stderr, and spans
+/// are exported to the OTLP collector of `OTEL_EXPORTER_OTLP_ENDPOINT`, or written to the
+/// file of `WKR_TRACE_FILE`. Without either, spans are only used to propagate the trace
+/// context of the requests.
+pub fn init() -> anyhow::Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let config = trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        "wkr",
+    )]));
+    let tracer = if let Ok(endpoint) = std::env::var(OTLP_ENDPOINT_ENV) {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(config)
+            .install_batch(opentelemetry::runtime::Tokio)?
+    } else if let Ok(path) = std::env::var(TRACE_FILE_ENV) {
+        opentelemetry::sdk::export::trace::stdout::new_pipeline()
+            .with_writer(File::create(path)?)
+            .with_trace_config(config)
+            .install_simple()
+    } else {
+        let provider = trace::TracerProvider::builder().with_config(config).build();
+        let tracer = provider.tracer("wkr");
+        global::set_tracer_provider(provider);
+        tracer
+    };
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        // stdout is left to the output of the commands, such as the response of `wkr invoke`
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(())
+}
+
+/// Exports the spans not sent yet
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Opens the span of a request, continuing the trace of its `traceparent` and `tracestate`
+/// headers when it has them. The calls of functions, their host calls and their fetches
+/// are children of that span.
+pub async fn trace_request(request: Request<Body>, next: Next<Body>) -> Response {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        http.method = %request.method(),
+        http.target = %request.uri(),
+        http.status_code = field::Empty,
+        request_id = %RequestContext::of(&request).id,
+    );
+    span.set_parent(parent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
@@ -1,9 +1,11 @@
-use wkr_core::{create_function_engine};
+use std::path::Path;
+use wkr_core::{create_function_engine, FunctionConfig};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let file = "/home/dallen/WorkerCodes/WASM/worker-script/testing/builds/myModule.wasm";
-    let mut environment = create_function_engine(file).await?;
+    let config = FunctionConfig::for_module(Path::new(file)).await?;
+    let mut environment = create_function_engine(file, &config).await?;
     environment.init().await?;
     let guest_result = environment.call("test", &vec![]).await?;
 
@@ -0,0 +1,187 @@
+use crate::error::FunctionError;
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart},
+    http::{header::CONTENT_TYPE, Request},
+};
+use std::path::{Path, PathBuf};
+use wkr_core::{
+    errors::Error as RuntimeError, inspect, module_cache_dir, EnvironmentBuilder, FunctionConfig,
+};
+
+/// Largest module accepted by `POST /functions/:function`
+pub const MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// A module uploaded along with its configuration
+pub struct Upload {
+    pub module: Bytes,
+    pub config: FunctionConfig,
+}
+
+/// Reads an upload: either the module itself, run with the default configuration, or a
+/// `multipart/form-data` body with a `module` part and an optional `config` part holding a
+/// TOML or JSON manifest.
+pub async fn read_upload<S: Send + Sync>(
+    request: Request<Body>,
+    state: &S,
+) -> Result<Upload, FunctionError> {
+    let multipart = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("multipart/form-data"));
+    if !multipart {
+        let module = Bytes::from_request(request, state)
+            .await
+            .map_err(|e| FunctionError::InvalidUpload(e.body_text()))?;
+        return Ok(Upload {
+            module,
+            config: FunctionConfig::default(),
+        });
+    }
+
+    let mut multipart = Multipart::from_request(request, state)
+        .await
+        .map_err(|e| FunctionError::InvalidUpload(e.body_text()))?;
+    let mut module = None;
+    let mut config = FunctionConfig::default();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| FunctionError::InvalidUpload(e.to_string()))?
+    {
+        let name = field.name().unwrap_or_default().to_owned();
+        let format = manifest_format(field.file_name(), field.content_type());
+        let bytes = field
+            .bytes()
+            .await
+            .map_err(|e| FunctionError::InvalidUpload(e.to_string()))?;
+        match name.as_str() {
+            "module" => module = Some(bytes),
+            "config" => {
+                config = FunctionConfig::from_manifest(&bytes, format)
+                    .map_err(|e| FunctionError::InvalidConfig(e.to_string()))?;
+            }
+            _ => {
+                return Err(FunctionError::InvalidUpload(format!(
+                    "unexpected part `{}`",
+                    name
+                )))
+            }
+        }
+    }
+
+    let module = module
+        .ok_or_else(|| FunctionError::InvalidUpload("missing `module` part".to_owned()))?;
+    Ok(Upload { module, config })
+}
+
+/// The format of a manifest part, according to its file name or its content type. JSON
+/// unless told otherwise.
+fn manifest_format(file_name: Option<&str>, content_type: Option<&str>) -> &'static str {
+    let toml = file_name.map_or(false, |name| name.ends_with(".toml"))
+        || content_type.map_or(false, |content_type| content_type.contains("toml"));
+    if toml {
+        "toml"
+    } else {
+        "json"
+    }
+}
+
+/// The host directories uploaded functions may expose to their guests, and the ones below
+/// them: `WKR_UPLOAD_DIRS`, a list of paths like `PATH`. None when unset.
+pub fn upload_dirs() -> Vec<PathBuf> {
+    std::env::var_os("WKR_UPLOAD_DIRS")
+        .map(|dirs| std::env::split_paths(&dirs).collect())
+        .unwrap_or_default()
+}
+
+/// Checks that `module` is a waPC guest and that `config` can run it, so the function is
+/// rejected before being published when it can't run. The module is compiled into the
+/// module cache on the way, with the engine configuration used to run it. The directories
+/// of `config` must be within `allowed_dirs`.
+pub async fn validate_module(
+    name: &str,
+    module: Bytes,
+    config: &FunctionConfig,
+    allowed_dirs: &[PathBuf],
+) -> Result<(), FunctionError> {
+    check_dirs(config, allowed_dirs)?;
+    let info = inspect(&module).map_err(|e| match e {
+        RuntimeError::InvalidModule(e) => FunctionError::InvalidModule(e),
+        e => FunctionError::InvalidModule(e.to_string()),
+    })?;
+    if !info.is_wapc_guest() {
+        return Err(FunctionError::InvalidModule(
+            "the module doesn't export `__guest_call`".to_owned(),
+        ));
+    }
+
+    let name = name.to_owned();
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        // a dry run of the configuration
+        let builder = EnvironmentBuilder::new(&module).cache_dir(module_cache_dir());
+        match config.apply(builder, &name).build() {
+            Ok(_) => Ok(()),
+            Err(e @ RuntimeError::InitializationFailed(_)) => {
+                Err(FunctionError::InvalidConfig(e.to_string()))
+            }
+            Err(e) => Err(FunctionError::InvalidModule(format!("{:#}", e))),
+        }
+    })
+    .await
+    .map_err(|e| FunctionError::Internal(e.to_string()))?
+}
+
+/// Rejects the host directories of `config` outside of `allowed_dirs`
+fn check_dirs(config: &FunctionConfig, allowed_dirs: &[PathBuf]) -> Result<(), FunctionError> {
+    for (guest, host) in &config.dirs {
+        let host = Path::new(host).canonicalize().map_err(|e| {
+            FunctionError::InvalidConfig(format!("cannot open directory `{}`: {}", host, e))
+        })?;
+        let allowed = allowed_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| host.starts_with(dir));
+        if !allowed {
+            return Err(FunctionError::InvalidConfig(format!(
+                "directory `{}` can't be exposed to `{}`, see WKR_UPLOAD_DIRS",
+                host.display(),
+                guest
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_formats() {
+        assert_eq!(manifest_format(Some("hello.toml"), None), "toml");
+        assert_eq!(manifest_format(None, Some("application/toml")), "toml");
+        assert_eq!(manifest_format(Some("hello.json"), Some("application/json")), "json");
+        assert_eq!(manifest_format(None, None), "json");
+    }
+
+    #[test]
+    fn upload_dirs_are_allowed_only() {
+        let allowed = std::env::temp_dir();
+        let mut config = FunctionConfig::default();
+        assert!(check_dirs(&config, &[]).is_ok());
+
+        config.dirs.insert("/data".to_owned(), allowed.to_string_lossy().into_owned());
+        assert!(check_dirs(&config, &[allowed.clone()]).is_ok());
+        assert!(check_dirs(&config, &[]).is_err());
+
+        config.dirs.insert("/root".to_owned(), "/".to_owned());
+        assert!(check_dirs(&config, &[allowed]).is_err());
+
+        config.dirs.clear();
+        config.dirs.insert("/data".to_owned(), "/does/not/exist".to_owned());
+        assert!(check_dirs(&config, &[PathBuf::from("/")]).is_err());
+    }
+}